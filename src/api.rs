@@ -1,148 +1,368 @@
+use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
 
-use reqwest::{Url};
+use reqwest::{Response, StatusCode, Url};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
 use tokio::join;
 
-use crate::args::Update;
-use crate::types::{Channel, DMChannel, Guild, Message, Settings, TextChannel, U64ReprStr};
+use crate::args::ClientOpts;
+use crate::ratelimit::{RateLimitedBody, RateLimiter};
+use crate::types::{Channel, DMChannel, Guild, Message, Settings, TextChannel, ThreadList, User};
+
+// How many 429s in a row we sit through before giving up on a request
+const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+
+// How much of a bad response body to keep around for the error message
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// 401. The token is wrong or expired, nothing else is going to work either
+    Unauthorized(String),
+    /// 403, usually Missing Access on a single channel
+    Forbidden(String),
+    /// 404, usually Unknown Channel
+    NotFound(String),
+    RateLimited { retry_after: f64 },
+    Server { status: StatusCode, body: String },
+    Decode { error: serde_json::Error, snippet: String },
+    Transport(reqwest::Error)
+}
 
+impl ApiError {
+    /// Whether trying the same request again later might work
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ApiError::Server { .. } | ApiError::Decode { .. } | ApiError::Transport(_) | ApiError::RateLimited { .. })
+    }
+}
 
-pub async fn fetch_dms(options: &Update) -> Vec<DMChannel> {
-    let client = reqwest::Client::new();
-    let mut url = Url::parse("https://discord.com").unwrap();
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Unauthorized(message) => write!(f, "unauthorized, is the token expired? ({message})"),
+            ApiError::Forbidden(message) => write!(f, "no access ({message})"),
+            ApiError::NotFound(message) => write!(f, "not found ({message})"),
+            ApiError::RateLimited { retry_after } => write!(f, "still rate limited, retry after {retry_after:.2}s"),
+            ApiError::Server { status, body } => write!(f, "server error {status}: {body}"),
+            ApiError::Decode { error, snippet } => write!(f, "bad response body ({error}): {snippet}"),
+            ApiError::Transport(e) => write!(f, "request failed: {e}")
+        }
+    }
+}
 
-    url.set_path("api/v6/users/@me/channels");
+impl std::error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApiError::Decode { error, .. } => Some(error),
+            ApiError::Transport(e) => Some(e),
+            _ => None
+        }
+    }
+}
 
-    client.get(url)
-        .header("Authorization", &options.token)
-        .send()
-        .await.unwrap()
-        .json::<Vec<DMChannel>>()
-        .await.unwrap()
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Transport(e)
+    }
 }
 
-pub async fn fetch_guilds(options: &Update) -> Vec<Guild> {
-    let client = reqwest::Client::new();
-    let mut guilds_url = Url::parse("https://discord.com").unwrap();
-    let mut settings_url = Url::parse("https://discord.com").unwrap();
+/// Where a page of messages is anchored. Discord always returns a page newest first
+#[derive(Debug, Clone, Copy)]
+pub enum Page {
+    After(u64),
+    Before(u64),
+    /// Up to half a page either side of it
+    Around(u64)
+}
 
-    guilds_url.set_path("api/v9/users/@me/guilds");
-    settings_url.set_path("api/v9/users/@me/settings");
+impl Page {
+    fn query(&self) -> (&'static str, u64) {
+        match *self {
+            Page::After(id) => ("after", id),
+            Page::Before(id) => ("before", id),
+            Page::Around(id) => ("around", id)
+        }
+    }
+}
 
-    let (guilds, settings) = join!(
-        client.get(guilds_url)
-            .header("Authorization", &options.token)
-            .send(),
-        client.get(settings_url)
-            .header("Authorization", &options.token)
-            .send()
-    );
-
-    let (guilds, settings) = join!(
-        guilds.unwrap().json::<Vec<Guild>>(),
-        settings.unwrap().json::<Settings>()
-    );
-
-    let (mut guilds, settings) = (guilds.unwrap(), settings.unwrap());
-
-    guilds.sort_by_cached_key(|guild|
-        // This could be more efficient...
-        settings.guild_ids()
-            .iter()
-            .enumerate()
-            .find(|(_, s)| **s == *guild.id)
-            .unwrap_or((0, &U64ReprStr(0)))
-            .0
-    );
-
-    guilds
+/// The three listings of archived threads in a channel
+#[derive(Debug, Clone, Copy)]
+pub enum ArchivedThreads {
+    Public,
+    /// Needs Manage Threads
+    Private,
+    /// Private threads we've been added to, which doesn't need Manage Threads
+    JoinedPrivate
 }
 
-pub async fn fetch_channels(options: &Update, guild_id: u64) -> Vec<TextChannel> {
-    let client = reqwest::Client::new();
-    let mut url = Url::parse("https://discord.com").unwrap();
+impl ArchivedThreads {
+    fn path(&self, channel_id: u64) -> String {
+        match self {
+            ArchivedThreads::Public => format!("channels/{channel_id}/threads/archived/public"),
+            ArchivedThreads::Private => format!("channels/{channel_id}/threads/archived/private"),
+            ArchivedThreads::JoinedPrivate => format!("channels/{channel_id}/users/@me/threads/archived/private")
+        }
+    }
+
+    /// The joined listing pages by thread id, the others by archive time
+    fn cursor(&self, last: &TextChannel) -> Option<String> {
+        match self {
+            ArchivedThreads::JoinedPrivate => Some(last.id.to_string()),
+            _ => last.thread_metadata.as_ref()?.archive_timestamp.clone()
+        }
+    }
+}
 
-    url.set_path(&format!("api/v6/guilds/{guild_id}/channels"));
+#[derive(Debug, Deserialize)]
+struct DiscordErrorBody {
+    message: String
+}
 
-    client.get(url)
-        .header("Authorization", &options.token)
-        .send()
-        .await.unwrap()
-        .json::<Vec<TextChannel>>()
-        .await.unwrap()
+fn snippet(body: &str) -> String {
+    match body.char_indices().nth(SNIPPET_LENGTH) {
+        Some((index, _)) => format!("{}...", &body[..index]),
+        None => body.to_string()
+    }
 }
 
-pub async fn fetch_channel(options: &Update, channel_id: u64) -> Channel {
-    let client = reqwest::Client::new();
-    let mut url = Url::parse("https://discord.com").unwrap();
+/// One connection pool and set of credentials, shared by everything that talks to Discord
+#[derive(Debug, Clone)]
+pub struct DiscordClient {
+    http: reqwest::Client,
+    token: String,
+    base_url: Url,
+    api_version: u8,
+    download_timeout: Duration,
+    limiter: Arc<RateLimiter>
+}
 
-    url.set_path(&format!("api/v6/channels/{channel_id}"));
+impl DiscordClient {
+    pub fn new(options: &ClientOpts) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(&options.user_agent)
+            .timeout(Duration::from_secs(options.timeout))
+            .connect_timeout(Duration::from_secs(options.connect_timeout))
+            .build()?;
 
-    client.get(url)
-        .header("Authorization", &options.token)
-        .send()
-        .await.unwrap()
-        .json::<Channel>()
-        .await.unwrap()
-}
+        let base_url = Url::parse(&options.base_url)?;
 
+        Ok(DiscordClient {
+            http,
+            token: options.token.clone(),
+            base_url,
+            api_version: options.api_version,
+            download_timeout: Duration::from_secs(options.download_timeout),
+            limiter: Arc::new(RateLimiter::new(options.global_rate_limit))
+        })
+    }
 
-pub async fn fetch_messages(token: &str, channel_id: u64, start_from: u64) -> anyhow::Result<Vec<Message>> {
-    let client = reqwest::Client::new();
-    let mut url = Url::parse("https://discord.com").unwrap();
+    fn url(&self, path: &str) -> Url {
+        let mut url = self.base_url.clone();
 
-    url.set_path(&format!("api/v6/channels/{channel_id}/messages"));
+        url.set_path(&format!("api/v{}/{path}", self.api_version));
 
-    url.query_pairs_mut().append_pair("limit", "100");
-    url.query_pairs_mut().append_pair("after", &format!["{start_from}"]);
+        url
+    }
 
-    let mut retries = 0;
-    let results = loop {
-        let response = client.get(url.clone())
-            .header("Authorization", token)
-            .send()
-            .await;
+    /// Sends an authorized GET, waiting out any rate limits before and after
+    async fn get(&self, url: Url) -> Result<Response, ApiError> {
+        let route = url.path().to_string();
+        let mut limited = 0;
 
-        let response = match response {
-            Ok(response) => response,
-            Err(e) => {
-                eprintln!("Failed while fetching messages {e:?}");
+        loop {
+            self.limiter.acquire(&route).await;
+
+            let response = self.http.get(url.clone())
+                .header("Authorization", &self.token)
+                .send()
+                .await?;
+
+            self.limiter.update(&route, response.headers());
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+
+            let scope_global = response.headers()
+                .get("x-ratelimit-global")
+                .is_some_and(|v| v == "true");
+            let header_retry = response.headers()
+                .get("retry-after")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<f64>().ok());
 
-                if retries > 3 {
-                    return Err(e.into());
-                }
+            let (retry_after, global) = match response.json::<RateLimitedBody>().await {
+                Ok(body) => (body.retry_after, body.global || scope_global),
+                Err(_) => (header_retry.unwrap_or(1.0), scope_global)
+            };
 
-                retries += 1;
+            limited += 1;
 
-                tokio::time::sleep(Duration::from_secs(retries * 3)).await;
+            if limited > MAX_RATE_LIMITED_RETRIES {
+                return Err(ApiError::RateLimited { retry_after });
+            }
+
+            eprintln!("rate limited on {route}{}; retrying in {retry_after:.2}s", if global { " (global)" } else { "" });
+
+            self.limiter.limited(&route, Duration::from_secs_f64(retry_after.max(0.0)), global);
+        }
+    }
+
+    /// Sends an authorized GET and decodes the body, turning unhappy statuses into an `ApiError`
+    async fn get_json<T: DeserializeOwned>(&self, url: Url) -> Result<T, ApiError> {
+        let response = self.get(url).await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        if !status.is_success() {
+            let message = serde_json::from_str::<DiscordErrorBody>(&body)
+                .map(|b| b.message)
+                .unwrap_or_else(|_| snippet(&body));
+
+            return Err(match status {
+                StatusCode::UNAUTHORIZED => ApiError::Unauthorized(message),
+                StatusCode::FORBIDDEN => ApiError::Forbidden(message),
+                StatusCode::NOT_FOUND => ApiError::NotFound(message),
+                status => ApiError::Server { status, body: message }
+            });
+        }
+
+        serde_json::from_str(&body).map_err(|error| ApiError::Decode {
+            error,
+            snippet: snippet(&body)
+        })
+    }
+
+    /// Fetches a file off the CDN. These don't take authorization
+    pub async fn fetch_bytes(&self, url: &str) -> reqwest::Result<Vec<u8>> {
+        let bytes = self.http.get(url)
+            .timeout(self.download_timeout)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+
+        Ok(bytes.to_vec())
+    }
+
+    /// The account the token belongs to
+    pub async fn fetch_me(&self) -> Result<User, ApiError> {
+        self.get_json(self.url("users/@me")).await
+    }
+
+    pub async fn fetch_dms(&self) -> Result<Vec<DMChannel>, ApiError> {
+        self.get_json(self.url("users/@me/channels")).await
+    }
+
+    pub async fn fetch_guilds(&self) -> Result<Vec<Guild>, ApiError> {
+        let (guilds, settings) = join!(
+            self.get_json::<Vec<Guild>>(self.url("users/@me/guilds")),
+            self.get_json::<Settings>(self.url("users/@me/settings"))
+        );
+
+        let mut guilds = guilds?;
+
+        // Folder order is only cosmetic, so don't fail over it
+        let guild_ids = match settings {
+            Ok(settings) => settings.guild_ids(),
+            Err(e) => {
+                eprintln!("Couldn't fetch settings, guilds will be unordered: {e}");
 
-                continue;
+                vec![]
             }
         };
 
-        let messages = response.json::<Vec<Message>>().await;
+        guilds.sort_by_cached_key(|guild|
+            // This could be more efficient...
+            guild_ids
+                .iter()
+                .enumerate()
+                .find(|(_, s)| **s == *guild.id)
+                .unwrap_or((0, &0))
+                .0
+        );
 
-        let messages = match messages {
-            Ok(messages) => messages,
-            Err(e) => {
-                eprintln!("Failed while parsing messages in {channel_id} from {start_from}:\n{e:?}");
+        Ok(guilds)
+    }
+
+    pub async fn fetch_channels(&self, guild_id: u64) -> Result<Vec<TextChannel>, ApiError> {
+        self.get_json(self.url(&format!("guilds/{guild_id}/channels"))).await
+    }
+
+    pub async fn fetch_channel(&self, channel_id: u64) -> Result<Channel, ApiError> {
+        self.get_json(self.url(&format!("channels/{channel_id}"))).await
+    }
+
+    /// Every active thread in a guild, across all of its channels
+    pub async fn fetch_active_threads(&self, guild_id: u64) -> Result<Vec<TextChannel>, ApiError> {
+        let list: ThreadList = self.get_json(self.url(&format!("guilds/{guild_id}/threads/active"))).await?;
+
+        Ok(list.threads)
+    }
 
-                if retries > 3 {
-                    return Err(e.into());
-                }
+    pub async fn fetch_archived_threads(&self, channel_id: u64, kind: ArchivedThreads) -> Result<Vec<TextChannel>, ApiError> {
+        let mut threads = vec![];
+        let mut before: Option<String> = None;
 
-                retries += 1;
+        loop {
+            let mut url = self.url(&kind.path(channel_id));
 
-                tokio::time::sleep(Duration::from_secs(3)).await;
+            url.query_pairs_mut().append_pair("limit", "100");
 
-                continue;
+            if let Some(before) = &before {
+                url.query_pairs_mut().append_pair("before", before);
             }
-        };
 
-        break messages;
-    };
+            let list: ThreadList = self.get_json(url).await?;
+
+            before = list.threads.last().and_then(|t| kind.cursor(t));
+            threads.extend(list.threads);
+
+            if !list.has_more || before.is_none() {
+                break;
+            }
+        }
+
+        Ok(threads)
+    }
+
+    pub async fn fetch_messages(&self, channel_id: u64, page: Page) -> Result<Vec<Message>, ApiError> {
+        let mut url = self.url(&format!("channels/{channel_id}/messages"));
+        let (anchor, start_from) = page.query();
+
+        url.query_pairs_mut().append_pair("limit", "100");
+        url.query_pairs_mut().append_pair(anchor, &format!["{start_from}"]);
+
+        let mut retries = 0;
+        let results = loop {
+            let messages = self.get_json::<Vec<Message>>(url.clone()).await;
+
+            let messages = match messages {
+                Ok(messages) => messages,
+                Err(e) if e.is_transient() => {
+                    eprintln!("Failed while fetching messages in {channel_id} {anchor} {start_from}:\n{e}");
+
+                    if retries > 3 {
+                        return Err(e);
+                    }
+
+                    retries += 1;
+
+                    tokio::time::sleep(Duration::from_secs(retries * 3)).await;
+
+                    continue;
+                },
+                Err(e) => return Err(e)
+            };
+
+            break messages;
+        };
 
-    // dbg!(results);
+        // dbg!(results);
 
-    Ok(results)
+        Ok(results)
+    }
 }
@@ -1,31 +1,145 @@
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use crate::store::Store;
 use crate::types::{
     Guild,
-    Channel
+    Channel,
+    Snowflake
 };
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
-pub enum DexporterOpts {
+pub struct DexporterOpts {
+    /// Where the archives are kept. Defaults to db in the current directory
+    #[arg(long, global = true, env = "DEXPORTER_DB")]
+    pub db: Option<PathBuf>,
+
+    /// Where export and download write to. Defaults to the current directory
+    #[arg(long, global = true, env = "DEXPORTER_OUT")]
+    pub out: Option<PathBuf>,
+
+    /// What update, import and export keep the archives in. Defaults to dex
+    #[arg(long, global = true, env = "DEXPORTER_STORE", value_enum)]
+    pub store: Option<Store>,
+
+    /// A TOML file with db, out and store keys, used for whichever of them isn't passed as a flag or in the environment.
+    /// Defaults to dexporter/config.toml in the platform's config directory, if there's one there
+    #[arg(long, global = true, env = "DEXPORTER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Command
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
     Import(Import),
     Export(Export),
     Update(Update),
-    Download(Download)
+    Download(Download),
+    History(History),
+    /// Rewrites every archive sorted, with edits and deletions folded in
+    Compact(Compact),
+    /// Checks every archive for damage and likely holes. Exits with 1 if it finds problems
+    Verify(Verify),
+    /// Upgrades archives written in an older format to the current one
+    Migrate(Migrate),
+    /// Converts archives to .dex.zst, which everything else reads just the same
+    Compress(Compress),
+    /// Converts .dex.zst archives back to plain .dex
+    Decompress(Decompress),
+    /// Builds a .dex.idx next to every uncompressed archive, so looking up a message or a date range
+    /// only reads the parts of it that matter. Archives update starts get one from the start, older ones
+    /// need this once. After that, update and import keep them up to date
+    Index(Index),
+    /// Moves every archive from one store to the other
+    Convert(Convert)
 }
 
-#[derive(Parser, Debug)]
-pub struct Update {
+#[derive(Parser, Debug, Clone)]
+pub struct ClientOpts {
     #[arg(long)]
     pub token: String,
 
+    /// Where the Discord API lives. Point this at a mock server for testing
+    #[arg(long, default_value = "https://discord.com")]
+    pub base_url: String,
+
+    #[arg(long, default_value_t = 9)]
+    pub api_version: u8,
+
+    #[arg(long, default_value = concat!("dexporter/", env!("CARGO_PKG_VERSION")))]
+    pub user_agent: String,
+
+    /// Per-request timeout, in seconds
+    #[arg(long, default_value_t = 30)]
+    pub timeout: u64,
+
+    /// How long an attachment download can take, in seconds. Apart from --timeout, since big files take a while
+    #[arg(long, default_value_t = 3600)]
+    pub download_timeout: u64,
+
+    /// Connection timeout, in seconds
+    #[arg(long, default_value_t = 10)]
+    pub connect_timeout: u64,
+
+    /// Most requests to send per second, across all routes
+    #[arg(long, default_value_t = 50)]
+    pub global_rate_limit: u32
+}
+
+#[derive(Parser, Debug)]
+pub struct Update {
+    #[command(flatten)]
+    pub client: ClientOpts,
+
     #[arg(long, value_delimiter = ',')]
     pub channels: Vec<String>,
 
     #[arg(long)]
     pub guilds: Vec<String>,
 
+    /// Only fetch messages from this date or snowflake on. Older history missing from an
+    /// existing archive is backfilled down to it, so `--since 0` fills in everything. Anything
+    /// newer than what's stored is fetched either way, so there's never a hole after it
+    #[arg(long, value_parser = Snowflake::from_id_or_date)]
+    pub since: Option<Snowflake>,
+
+    /// Only fetch messages up to this date or snowflake. A date is up to the end of that day
+    #[arg(long, value_parser = Snowflake::until_id_or_date)]
+    pub until: Option<Snowflake>,
+
+    /// Don't look for threads in the selected channels
+    #[arg(long)]
+    pub skip_threads: bool,
+
+    /// Walk the whole channel, or just --since to --until, again and compare it with the archive.
+    /// Catches edits to older messages, and marks messages that are gone as deleted
+    #[arg(long)]
+    pub rescan: bool,
+
+    /// Look for stretches in the archive where messages were likely missed, like pages that failed
+    /// or runs that were cut short, and refetch just those
+    #[arg(long, conflicts_with = "rescan")]
+    pub repair: bool,
+
+    #[command(flatten)]
+    pub gaps: GapOpts,
+
+    /// If another dexporter is writing to the db, wait for it instead of giving up
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Skip over lines in the archives that can't be read, instead of stopping. They're moved to a
+    /// .quarantine file next to the archive when it's next rewritten
+    #[arg(long)]
+    pub salvage: bool,
+
+    /// Start new archives compressed, as .dex.zst. Existing ones stay as they are, see compress
+    #[arg(long)]
+    pub compress: bool,
+
     #[arg(skip)]
     pub state: UpdateState
 }
@@ -41,20 +155,164 @@ pub struct UpdateState {
 pub struct Import {
     #[arg(long)]
     pub file: PathBuf,
+
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Skip over unreadable lines in the archive, see update --salvage
+    #[arg(long)]
+    pub salvage: bool,
 }
 
 #[derive(Parser, Debug)]
 pub struct Export {
     #[arg(long)]
     pub headers: bool,
+
+    /// List earlier versions of edited messages under them
+    #[arg(long)]
+    pub edits: bool,
+
+    /// Export what can be read from broken archives instead of stopping at the first bad line
+    #[arg(long)]
+    pub salvage: bool,
+
+    /// Only export messages from this date or snowflake on
+    #[arg(long, value_parser = Snowflake::from_id_or_date)]
+    pub since: Option<Snowflake>,
+
+    /// Only export messages up to this date or snowflake. A date is up to the end of that day
+    #[arg(long, value_parser = Snowflake::until_id_or_date)]
+    pub until: Option<Snowflake>,
 }
 
+#[derive(Parser, Debug)]
+pub struct Compact {
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Rewrite archives with unreadable lines too, moving those lines to a .quarantine file
+    #[arg(long)]
+    pub salvage: bool,
+}
+
+/// What counts as a suspicious gap between two archived messages
+#[derive(Parser, Debug, Clone)]
+pub struct GapOpts {
+    /// How many of the channel's typical gaps between messages a gap has to span to be suspicious
+    #[arg(long, default_value_t = 100)]
+    pub gap_factor: u32,
+
+    /// Gaps shorter than this many hours are never suspicious
+    #[arg(long, default_value_t = 24)]
+    pub min_gap_hours: i64
+}
 
 #[derive(Parser, Debug)]
-pub struct Download {
+pub struct Verify {
+    #[command(flatten)]
+    pub gaps: GapOpts,
+
+    /// Fail on warnings too: imported messages, and suspicious gaps
     #[arg(long)]
-    pub token: String,
+    pub strict: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Migrate {
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Upgrade archives with unreadable lines too, moving those lines to a .quarantine file
+    #[arg(long)]
+    pub salvage: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Compress {
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Convert archives with unreadable lines too, moving those lines to a .quarantine file
+    #[arg(long)]
+    pub salvage: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Decompress {
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Convert archives with unreadable lines too, moving those lines to a .quarantine file
+    #[arg(long)]
+    pub salvage: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Index {
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Delete the indexes instead
+    #[arg(long)]
+    pub remove: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct Convert {
+    /// The store to move the archives to. They're read from the other one
+    #[arg(long, value_enum)]
+    pub to: Store,
+
+    #[arg(long)]
+    pub wait: bool,
+
+    /// Convert archives with unreadable lines too, moving those lines to a .quarantine file
+    #[arg(long)]
+    pub salvage: bool,
+
+    /// Write new .dex archives compressed, as .dex.zst
+    #[arg(long)]
+    pub compress: bool,
+
+    /// Leave the originals where they are
+    #[arg(long)]
+    pub keep: bool,
+}
+
+#[derive(Parser, Debug)]
+pub struct History {
+    /// The message to show every stored version of
+    #[arg(long)]
+    pub message: Snowflake,
+
+    /// Only look in this channel's archive, instead of all of them
+    #[arg(long)]
+    pub channel: Option<Snowflake>,
+
+    /// Look through what can be read of broken archives instead of skipping them
+    #[arg(long)]
+    pub salvage: bool,
+}
+
+
+#[derive(Parser, Debug)]
+pub struct Download {
+    #[command(flatten)]
+    pub client: ClientOpts,
 
     #[arg(long, value_delimiter = ',')]
-    pub channels: Vec<String>
+    pub channels: Vec<String>,
+
+    /// Only download attachments from this date or snowflake on
+    #[arg(long, value_parser = Snowflake::from_id_or_date)]
+    pub since: Option<Snowflake>,
+
+    /// Only download attachments up to this date or snowflake. A date is up to the end of that day
+    #[arg(long, value_parser = Snowflake::until_id_or_date)]
+    pub until: Option<Snowflake>,
+
+    /// Only download attachments from the page of messages around this date or snowflake, a single request
+    #[arg(long, value_parser = Snowflake::from_id_or_date, conflicts_with_all = ["since", "until"])]
+    pub around: Option<Snowflake>
 }
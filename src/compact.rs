@@ -0,0 +1,36 @@
+use walkdir::WalkDir;
+
+use crate::{args::Compact, config::Paths, fs::{DbLock, ParsedDex, is_dex}};
+
+pub async fn do_compact(compact: Compact, paths: &Paths) -> anyhow::Result<()> {
+    paths.require_dex("compact")?;
+
+    let _lock = DbLock::acquire(&paths.db, compact.wait).await?;
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if !is_dex(entry.path()) {
+            continue;
+        }
+
+        let mut parsed = match ParsedDex::parse(entry.path(), compact.salvage).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping: {e}");
+
+                continue;
+            }
+        };
+
+        let before = entry.metadata()?.len();
+
+        parsed.compact(entry.path()).await?;
+
+        let after = std::fs::metadata(entry.path())?.len();
+
+        eprintln!("compacted: {} {} -> {} bytes", entry.path().display(), before, after);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use walkdir::WalkDir;
+
+use crate::{args::{Compress, Decompress}, config::Paths, fs::{DbLock, ParsedDex, is_compressed, is_dex, toggle_compressed}, index::DexIndex};
+
+pub async fn do_compress(compress: Compress, paths: &Paths) -> anyhow::Result<()> {
+    convert(paths, compress.wait, compress.salvage, true).await
+}
+
+pub async fn do_decompress(decompress: Decompress, paths: &Paths) -> anyhow::Result<()> {
+    convert(paths, decompress.wait, decompress.salvage, false).await
+}
+
+/// Rewrites every archive that isn't already in the form asked for in it, then removes the original
+async fn convert(paths: &Paths, wait: bool, salvage: bool, compress: bool) -> anyhow::Result<()> {
+    paths.require_dex(if compress { "compress" } else { "decompress" })?;
+
+    let _lock = DbLock::acquire(&paths.db, wait).await?;
+
+    let mut before = 0;
+    let mut after = 0;
+
+    // Collected first, since we're adding files to what's being walked
+    let mut archives = vec![];
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if is_dex(entry.path()) && is_compressed(entry.path()) != compress {
+            archives.push(entry.into_path());
+        }
+    }
+
+    for path in archives {
+        let target = toggle_compressed(&path);
+
+        if target.exists() {
+            eprintln!("skipping: {}; {} is already there", path.display(), target.display());
+
+            continue;
+        }
+
+        let mut parsed = match ParsedDex::parse(&path, salvage).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping: {e}");
+
+                continue;
+            }
+        };
+
+        let size = std::fs::metadata(&path)?.len();
+
+        parsed.compact(&target).await?;
+        tokio::fs::remove_file(&path).await?;
+
+        // Offsets into the old file. `index` makes a new one for decompressed archives
+        let _ = tokio::fs::remove_file(DexIndex::path_for(&path)).await;
+
+        let new_size = std::fs::metadata(&target)?.len();
+
+        eprintln!("{}: {} {size} -> {new_size} bytes", if compress { "compressed" } else { "decompressed" }, target.display());
+
+        before += size;
+        after += new_size;
+    }
+
+    eprintln!("done: {before} -> {after} bytes");
+
+    Ok(())
+}
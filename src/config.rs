@@ -0,0 +1,98 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::args::DexporterOpts;
+use crate::sqlite::SQLITE_FILE;
+use crate::store::Store;
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// The config file, `config.toml` in the platform's config directory under `dexporter`, or wherever
+/// --config points. Flags and environment variables take priority over it
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    db: Option<PathBuf>,
+    out: Option<PathBuf>,
+    store: Option<Store>
+}
+
+impl Config {
+    /// A missing file is only an error if it was asked for by name
+    fn load(opts: &DexporterOpts) -> anyhow::Result<(Self, PathBuf)> {
+        let (path, explicit) = match &opts.config {
+            Some(path) => (path.clone(), true),
+            None => match dirs::config_dir() {
+                Some(dir) => (dir.join("dexporter").join(CONFIG_FILE), false),
+                None => return Ok((Config::default(), PathBuf::new()))
+            }
+        };
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => return Ok((Config::default(), path)),
+            Err(e) => return Err(anyhow::anyhow!("couldn't read {}: {e}", path.display()))
+        };
+
+        let config = toml::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("bad config file {}: {e}", path.display()))?;
+
+        Ok((config, path))
+    }
+}
+
+/// Where everything is read from and written to
+#[derive(Debug, Clone)]
+pub struct Paths {
+    /// The archives, `db` in the current directory unless set otherwise
+    pub db: PathBuf,
+    /// Where `export` and `download` go, the current directory unless set otherwise
+    pub out: PathBuf,
+    /// What the archives in `db` are kept in, `.dex` files unless set otherwise
+    pub store: Store
+}
+
+impl Paths {
+    pub fn resolve(opts: &DexporterOpts) -> anyhow::Result<Self> {
+        let (config, config_path) = Config::load(opts)?;
+
+        // Relative paths in the config file are relative to the file, so they mean the same thing from anywhere
+        let relative_to_config = |path: PathBuf| match config_path.parent() {
+            Some(dir) => dir.join(path),
+            None => path
+        };
+
+        let db = opts.db.clone()
+            .or_else(|| config.db.map(relative_to_config))
+            .unwrap_or_else(|| PathBuf::from("db"));
+        let out = opts.out.clone()
+            .or_else(|| config.out.map(relative_to_config))
+            .unwrap_or_else(|| PathBuf::from("."));
+        let store = opts.store.or(config.store).unwrap_or_default();
+
+        Ok(Paths { db, out, store })
+    }
+
+    pub fn export(&self) -> PathBuf {
+        self.out.join("export")
+    }
+
+    pub fn download(&self) -> PathBuf {
+        self.out.join("download")
+    }
+
+    /// The database `--store sqlite` uses
+    pub fn sqlite(&self) -> PathBuf {
+        self.db.join(SQLITE_FILE)
+    }
+
+    /// For commands that work on `.dex` files directly
+    pub fn require_dex(&self, command: &str) -> anyhow::Result<()> {
+        if self.store != Store::Dex {
+            return Err(anyhow::anyhow!("{command} only works on .dex archives, use convert --to dex first"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashSet;
+
+use walkdir::WalkDir;
+
+use crate::{args::Convert, config::Paths, fs::{DbLock, ParsedDex, dex_path, is_dex, toggle_compressed}, index::DexIndex, sqlite::SqliteDb, store::Store, types::Snowflake};
+
+pub async fn do_convert(convert: Convert, paths: &Paths) -> anyhow::Result<()> {
+    let _lock = DbLock::acquire(&paths.db, convert.wait).await?;
+
+    match convert.to {
+        Store::Sqlite => to_sqlite(&convert, paths, &SqliteDb::open(&paths.sqlite())?).await,
+        Store::Dex => to_dex(&convert, paths, &SqliteDb::open_existing(&paths.sqlite())?).await
+    }
+}
+
+/// Copies every `.dex` into the database, then removes it. Channels that are already in there are left alone
+async fn to_sqlite(convert: &Convert, paths: &Paths, db: &SqliteDb) -> anyhow::Result<()> {
+    let existing: HashSet<Snowflake> = db.channels()?.into_iter().collect();
+
+    let mut converted = 0;
+    let mut messages = 0;
+
+    // Collected first, since we're removing files from what's being walked
+    let mut archives = vec![];
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if is_dex(entry.path()) {
+            archives.push(entry.into_path());
+        }
+    }
+
+    for path in archives {
+        let mut parsed = match ParsedDex::parse(&path, convert.salvage).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping: {e}");
+
+                continue;
+            }
+        };
+
+        let channel = Snowflake(parsed.header.channel.id());
+
+        if existing.contains(&channel) {
+            eprintln!("skipping: {}; channel {channel} is already in {}", path.display(), db.path().display());
+
+            continue;
+        }
+
+        db.replace(&mut parsed)?;
+
+        // The database has no place for them, and the .dex they came from is about to go
+        parsed.quarantine(&path).await?;
+
+        if !convert.keep {
+            tokio::fs::remove_file(&path).await?;
+
+            let _ = tokio::fs::remove_file(DexIndex::path_for(&path)).await;
+        }
+
+        eprintln!("converted: {} -> {} ({} messages)", path.display(), db.path().display(), parsed.messages.len());
+
+        converted += 1;
+        messages += parsed.messages.len();
+    }
+
+    eprintln!("done: {converted} archives, {messages} messages");
+
+    Ok(())
+}
+
+/// Writes every channel in the database out as a `.dex`, then drops it from the database. Channels that
+/// already have a `.dex` are left alone
+async fn to_dex(convert: &Convert, paths: &Paths, db: &SqliteDb) -> anyhow::Result<()> {
+    let mut converted = 0;
+    let mut messages = 0;
+
+    for channel in db.channels()? {
+        let Some(mut parsed) = db.load(channel, None)? else {
+            continue;
+        };
+
+        let plain = dex_path(&paths.db, &parsed.header.channel);
+        let compressed = toggle_compressed(&plain);
+
+        if plain.exists() || compressed.exists() {
+            eprintln!("skipping: channel {channel}; {} is already there", plain.display());
+
+            continue;
+        }
+
+        let target = if convert.compress { compressed } else { plain };
+
+        tokio::fs::create_dir_all(target.parent().unwrap()).await?;
+
+        parsed.compact(&target).await?;
+
+        if !convert.keep {
+            db.remove(channel)?;
+        }
+
+        eprintln!("converted: channel {channel} -> {} ({} messages)", target.display(), parsed.messages.len());
+
+        converted += 1;
+        messages += parsed.messages.len();
+    }
+
+    eprintln!("done: {converted} archives, {messages} messages");
+
+    Ok(())
+}
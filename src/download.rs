@@ -1,22 +1,25 @@
-use crate::{api::fetch_messages, args::Download, types::Attachment};
+use std::path::Path;
 
-pub async fn download_attachment(attachment: &Attachment, channel: &str) -> Result<(), anyhow::Error> {
-    tokio::fs::create_dir_all(&format!("download/{channel}")).await?;
+use crate::{api::{DiscordClient, Page}, args::Download, config::Paths, types::{Attachment, Message, Snowflake}};
 
-    let buffer = reqwest::get(&attachment.url).await?.bytes().await?;
+pub async fn download_attachment(client: &DiscordClient, attachment: &Attachment, dir: &Path) -> Result<(), anyhow::Error> {
+    tokio::fs::create_dir_all(dir).await?;
 
-    tokio::fs::write(&format!("download/{channel}/{}.{}", attachment.id, attachment.filename), buffer).await?;
+    let buffer = client.fetch_bytes(&attachment.url).await?;
+
+    tokio::fs::write(dir.join(format!("{}.{}", attachment.id, attachment.filename)), buffer).await?;
 
     Ok(())
 }
 
-pub async fn download_channel(channel: &str, token: &str) -> Result<(), anyhow::Error> {
-    let mut start_from = 0;
+pub async fn download_channel(client: &DiscordClient, channel: &str, dir: &Path, since: Option<Snowflake>, until: Option<Snowflake>) -> Result<(), anyhow::Error> {
+    let mut start_from = since.map_or(0, |s| *s);
+    let until = until.map_or(u64::MAX, |u| *u);
 
-    for _ in 1u32.. {
+    while start_from < until {
         eprintln!("fetching: {} from: {}", channel, start_from);
 
-        let messages = fetch_messages(token, channel.parse().unwrap(), start_from).await?;
+        let messages = client.fetch_messages(channel.parse().unwrap(), Page::After(start_from)).await?;
 
         if messages.is_empty() {
             break;
@@ -31,26 +34,48 @@ pub async fn download_channel(channel: &str, token: &str) -> Result<(), anyhow::
             start_from = start_from.max(*last.id);
         }
 
-        for message in messages {
-            for attachment in message.attachments {
-                match download_attachment(&attachment, channel).await {
-                    Ok(_) => eprintln!("downloaded: {}", attachment.url),
-                    Err(_) => eprintln!("failed: {}", attachment.url),
-                }
-            }
-        }
+        download_attachments(client, messages.into_iter().filter(|m| *m.id <= until).collect(), dir).await;
     }
 
     Ok(())
 }
 
-pub async fn do_download(args: Download) -> Result<(), anyhow::Error> {
+/// The attachments of the messages around `around`, for when only the ones near a single message are wanted
+pub async fn download_around(client: &DiscordClient, channel: &str, dir: &Path, around: Snowflake) -> Result<(), anyhow::Error> {
+    eprintln!("fetching: {} around: {}", channel, around);
+
+    let messages = client.fetch_messages(channel.parse().unwrap(), Page::Around(*around)).await?;
+
+    download_attachments(client, messages, dir).await;
+
+    Ok(())
+}
+
+async fn download_attachments(client: &DiscordClient, messages: Vec<Message>, dir: &Path) {
+    for message in messages {
+        for attachment in message.attachments {
+            match download_attachment(client, &attachment, dir).await {
+                Ok(_) => eprintln!("downloaded: {}", attachment.url),
+                Err(_) => eprintln!("failed: {}", attachment.url),
+            }
+        }
+    }
+}
+
+pub async fn do_download(args: Download, paths: &Paths) -> Result<(), anyhow::Error> {
     if args.channels.is_empty() {
         return Err(anyhow::anyhow!("The --channels should not be empty. Pass in a list of the ids necessary."));
     }
 
+    let client = DiscordClient::new(&args.client)?;
+
     for channel in args.channels {
-        download_channel(&channel, &args.token).await?;
+        let dir = paths.download().join(&channel);
+
+        match args.around {
+            Some(around) => download_around(&client, &channel, &dir, around).await?,
+            None => download_channel(&client, &channel, &dir, args.since, args.until).await?
+        }
     }
 
     Ok(())
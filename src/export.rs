@@ -1,74 +1,106 @@
-use std::{borrow::Cow, path::PathBuf};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
-use crate::{args::Export, fs::ParsedDex, types::{Channel}};
+use crate::{args::Export, config::Paths, fs::{ParsedDex, dex_path, is_dex}, sqlite::SqliteDb, store::Store, types::{Account, Channel, Snowflake}};
 use chrono::{Datelike, Timelike};
 use tokio::io::{AsyncWriteExt, BufWriter};
 use walkdir::WalkDir;
 
-pub async fn do_export(export: Export) -> anyhow::Result<()> {
+pub async fn do_export(export: Export, paths: &Paths) -> anyhow::Result<()> {
     let mut parsoids = vec![];
 
-    for entry in WalkDir::new("db") {
-        let entry = entry?;
+    let range = (export.since.is_some() || export.until.is_some())
+        .then(|| export.since.unwrap_or_default()..=export.until.unwrap_or(Snowflake(u64::MAX)));
 
-        if entry.path().extension().map(|s| s.to_string_lossy()) != Some(Cow::Borrowed("dex")) {
-            continue;
-        }
+    match paths.store {
+        Store::Dex => {
+            for entry in WalkDir::new(&paths.db) {
+                let entry = entry?;
 
-        println!("{}", entry.path().display());
+                if !is_dex(entry.path()) {
+                    continue;
+                }
 
-        let Some(parsed) = ParsedDex::parse(entry.path()).await else {
-            return Err(anyhow::anyhow!("Invalid .dex file."));
-        };
+                println!("{}", entry.path().display());
 
-        parsoids.push((parsed, entry));
-    }
+                let parsed = match &range {
+                    Some(range) => ParsedDex::parse_range(entry.path(), range.clone(), export.salvage).await?,
+                    None => ParsedDex::parse(entry.path(), export.salvage).await?
+                };
 
-    // Recipients doesn't include self
+                parsoids.push((parsed, entry.path().strip_prefix(&paths.db)?.to_path_buf()));
+            }
+        },
+        Store::Sqlite => {
+            let db = SqliteDb::open_existing(&paths.sqlite())?;
 
-    // let mut shared_user_ids: Option<HashSet<U64ReprStr>> = None;
+            for channel in db.channels()? {
+                println!("{} channel {channel}", db.path().display());
 
-    // for (parsed, _) in parsoids.iter() {
-    //     if let Channel::DMChannel(dm) = &parsed.header {
-    //         let ourset = HashSet::from_iter(dm.recipients.iter().map(|r| r.id));
+                let Some(parsed) = db.load(channel, range.clone())? else {
+                    continue;
+                };
 
-    //         eprintln!("{ourset:?}");
+                // Laid out the same as if it was a .dex
+                let relative = dex_path(Path::new(""), &parsed.header.channel);
 
-    //         if let Some(shared) = shared_user_ids {
-    //             shared_user_ids = Some(shared.intersection(&ourset).copied().collect());
-    //         } else {
-    //             shared_user_ids = Some(ourset);
-    //         }
-    //     }
-    // }
+                parsoids.push((parsed, relative));
+            }
+        }
+    }
 
-    // if let Some(shared) = &shared_user_ids {
-    //     assert_eq!(shared.len(), 1);
-    // }
+    // Recipients doesn't include self, but it can't hurt to make sure for the older, imported or group ones
+    let account = Account::load(&paths.db).await;
+    let me = account.as_ref().map(|a| a.user.id);
+
+    match &account {
+        Some(account) => eprintln!("archive of: {} ({})", account.user.username, account.user.id),
+        None => eprintln!("no account manifest, run update to record whose archive this is"),
+    }
 
-    for (parsed, entry) in parsoids {
-        let mut components = entry.path().components();
-        components.next();
-        components.next_back();
+    let names: Vec<String> = parsoids.iter()
+        .map(|(parsed, _)| match &parsed.header.channel {
+            Channel::DMChannel(dm) => file_name(&dm.display_name_for(me)),
+            Channel::TextChannel(text_channel) => file_name(&text_channel.name),
+        })
+        .collect();
 
-        let mut non_db_path = PathBuf::new();
-        non_db_path.push("export");
-        non_db_path.push(components.as_path());
+    // Names aren't unique, two threads in a channel can be called the same. Case too, for filesystems that ignore it
+    let mut taken: HashMap<(Option<PathBuf>, String), usize> = HashMap::new();
 
-        let names: String = match &parsed.header {
-            Channel::DMChannel(dm) => dm.recipients.iter()
-                // .filter(|r| shared_user_ids.as_ref().unwrap().contains(&r.id))
-                .map(|r| r.username.to_string())
-                .collect::<Vec<_>>()
-                .join(", "),
-            Channel::TextChannel(text_channel) => text_channel.name.to_string(),
+    for ((_, relative), name) in parsoids.iter().zip(&names) {
+        *taken.entry((relative.parent().map(Path::to_path_buf), name.to_lowercase())).or_default() += 1;
+    }
+
+    for ((parsed, relative), names) in parsoids.into_iter().zip(names) {
+        // Same layout as the db, DMs and guild ids
+        let mut non_db_path = paths.export();
+
+        if let Some(parent) = relative.parent() {
+            non_db_path.push(parent);
+        }
+
+        let id = parsed.header.channel.id();
+        let names = if names.is_empty() {
+            id.to_string()
+        } else if taken[&(relative.parent().map(Path::to_path_buf), names.to_lowercase())] > 1 {
+            format!("{names} ({id})")
+        } else {
+            names
         };
 
         tokio::fs::create_dir_all(&non_db_path).await.unwrap();
 
         non_db_path.push(format!("{}.txt", names));
 
-        eprintln!("{} {}", non_db_path.display(), parsed.messages.len());
+        match &account {
+            Some(account) => {
+                let mine = parsed.messages.iter().filter(|m| Some(m.author.id) == me).count();
+
+                eprintln!("{} {} ({} from {})", non_db_path.display(), parsed.messages.len(), mine, account.user.username);
+            },
+            None => eprintln!("{} {}", non_db_path.display(), parsed.messages.len()),
+        }
 
         let file = tokio::fs::OpenOptions::new()
             .write(true)
@@ -79,9 +111,16 @@ pub async fn do_export(export: Export) -> anyhow::Result<()> {
         let mut file = BufWriter::new(file);
 
         let mut last_date = None;
+        let mut history = parsed.header.history.iter().peekable();
 
         for message in parsed.messages.iter() {
             let ts = message.timestamp.unwrap();
+
+            // Only known to have happened by the update that saw it, so it goes before anything sent after that
+            while let Some(change) = history.next_if(|c| c.seen_at <= ts) {
+                file.write_all(format!("{} {}\n", change.seen_at.format("%Y-%m-%d %H:%M:%S"), change.field.describe()).as_bytes()).await?;
+            }
+
             let year = ts.year();
             let month = ts.month();
             let day = ts.day();
@@ -114,17 +153,83 @@ pub async fn do_export(export: Export) -> anyhow::Result<()> {
 
             file.write_all(format!("{year:02}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02} {author}").as_bytes()).await?;
 
-            if let Some(content) = &message.content {
+            if let Some(change) = message.membership_change() {
+                let action = match (change.joined, change.by == change.user_id) {
+                    (true, _) => format!(" added {}", change.username),
+                    (false, true) => String::from(" left"),
+                    (false, false) => format!(" removed {}", change.username)
+                };
+
+                file.write_all(action.as_bytes()).await?;
+            } else if let Some(content) = &message.content {
                 file.write_all(format!(": {content}").as_bytes()).await?;
             }
 
+            if message.deleted_at.is_some() {
+                file.write_all(b" (deleted)").await?;
+            }
+
             for attachment in &message.attachments {
                 file.write_all(format!("\n{}", attachment.url).as_bytes()).await?;
             }
 
+            if export.edits {
+                for revision in &message.revisions {
+                    let when = revision.edited_timestamp.as_deref().unwrap_or("original");
+                    let content = revision.content.as_deref().unwrap_or("");
+
+                    file.write_all(format!("\n    ({when}): {content}").as_bytes()).await?;
+                }
+            }
+
             file.write_all(b"\n").await?;
         }
+
+        for change in history {
+            file.write_all(format!("{} {}\n", change.seen_at.format("%Y-%m-%d %H:%M:%S"), change.field.describe()).as_bytes()).await?;
+        }
+
+        file.flush().await?;
     }
 
     Ok(())
 }
+
+// Most filesystems cap names at 255 bytes, which leaves room for an id and the extension
+const MAX_NAME_BYTES: usize = 200;
+
+/// A channel's name, which can be anything, made into something that can be a file name everywhere.
+/// Empty if there's nothing left of it
+fn file_name(name: &str) -> String {
+    let mut file_name = String::new();
+
+    for c in name.chars() {
+        if file_name.len() + c.len_utf8() > MAX_NAME_BYTES {
+            break;
+        }
+
+        file_name.push(match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c
+        });
+    }
+
+    // Windows drops them from the end, and "." and ".." are taken
+    file_name.trim_end_matches(['.', ' ']).trim_start().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_become_file_names() {
+        assert_eq!(file_name("Q&A / help"), "Q&A _ help");
+        assert_eq!(file_name("what? <no>: \"a|b\\c*\""), "what_ _no__ _a_b_c__");
+        assert_eq!(file_name("line\nbreak"), "line_break");
+        assert_eq!(file_name(".."), "");
+        assert_eq!(file_name(" trailing. "), "trailing");
+        assert_eq!(file_name(&"é".repeat(150)).len(), MAX_NAME_BYTES);
+    }
+}
@@ -1,14 +1,24 @@
-use std::fs::OpenOptions;
-use std::path::Path;
-use std::io::{BufRead, BufReader};
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::fs::{OpenOptions, TryLockError};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::io::{BufRead, BufReader, Seek as _, Write as _};
 
+use chrono::{DateTime, SubsecRound, TimeDelta, Utc};
 use futures::stream::{self, StreamExt};
 use tokio::fs;
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncWriteExt as _, BufWriter};
 
-use crate::api::fetch_messages;
+use crate::api::{ApiError, DiscordClient, Page};
 use crate::args::Update;
-use crate::types::{Channel, Message};
+use crate::index::{DexIndex, read_regions};
+use crate::shutdown::Shutdown;
+use crate::store::{Archive, Archives};
+use crate::types::{Account, Channel, ChannelChange, Message, Snowflake};
 
 // #[derive(Serialize, Deserialize)]
 // struct ChannelInfo {
@@ -26,162 +36,1754 @@ use crate::types::{Channel, Message};
 
 // }
 
+const ACCOUNT_FILE: &str = "account.json";
+const LOCK_FILE: &str = ".lock";
+
+/// An exclusive lock on a db directory, held for as long as this is alive, so two processes
+/// don't write the same archives at once. It's advisory, readers like export don't take it
 #[derive(Debug)]
-pub struct ParsedDex {
-    pub header: Channel,
-    pub messages: imbl::Vector<Message>
+pub struct DbLock {
+    _file: std::fs::File
 }
 
-impl ParsedDex {
-    pub async fn parse(file_path: &Path) -> Option<Self> {
-        let file = OpenOptions::new()
+impl DbLock {
+    /// Takes the lock, or if another process has it, fails or waits for it depending on `wait`
+    pub async fn acquire(db: &Path, wait: bool) -> anyhow::Result<Self> {
+        fs::create_dir_all(db).await?;
+
+        let path = db.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
             .read(true)
-            // We need write just to create it
             .write(true)
             .create(true)
             .truncate(false)
-            .open(file_path)
-            .unwrap();
+            .open(&path)?;
 
-        let file = BufReader::new(file);
+        match file.try_lock() {
+            Ok(()) => {},
+            Err(TryLockError::WouldBlock) => {
+                let holder = match std::fs::read_to_string(&path) {
+                    Ok(pid) if !pid.trim().is_empty() => format!(" (pid {})", pid.trim()),
+                    _ => String::new()
+                };
 
-        let mut lines = file.lines();
+                if !wait {
+                    return Err(anyhow::anyhow!(
+                        "{} is in use by another dexporter{holder}. Wait for it to finish, or pass --wait",
+                        db.display()
+                    ));
+                }
 
-        let Some(Ok(first_line)) = lines.next() else {
-            return None;
-        };
+                eprintln!("waiting for another dexporter{holder} to finish with {}", db.display());
 
-        let Ok(header) = serde_json::from_str(&first_line) else {
-            eprintln!("Failed to parse channel");
+                file = tokio::task::spawn_blocking(move || file.lock().map(|_| file)).await??;
+            },
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
 
-            return None;
-        };
+        // Only for the error message above. The lock itself is what counts
+        file.set_len(0)?;
+        file.write_all(std::process::id().to_string().as_bytes())?;
 
-        let mut messages = imbl::vector![];
+        Ok(DbLock { _file: file })
+    }
+}
 
-        for line in lines {
-            if let Ok(line) = line {
-                if let Ok(message) = serde_json::from_str(&line) {
-                    messages.push_back(message);
-                } else {
-                    eprintln!("line failed to parse: {line}");
-                }
-            } else {
-                eprintln!("Failed to read next line. File is likely corrupted.");
+/// Makes a rename in a directory durable. Not every platform can open directories, so this is best effort
+async fn sync_parent(path: &Path) {
+    if let Some(parent) = path.parent()
+        && let Ok(dir) = tokio::fs::File::open(parent).await
+    {
+        let _ = dir.sync_all().await;
+    }
+}
+
+/// Writes `bytes` to a temporary file next to `path`, and only renames it over `path` once it's fully on disk,
+/// so a crash or a full disk partway through leaves the old file intact
+async fn replace_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .await?;
+
+    file.write_all(bytes).await?;
+    file.sync_all().await?;
+
+    drop(file);
+
+    fs::rename(&temp_path, path).await?;
+    sync_parent(path).await;
+
+    Ok(())
+}
+
+impl Account {
+    pub async fn load(db: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(db.join(ACCOUNT_FILE)).await.ok()?;
+
+        match serde_json::from_str(&contents) {
+            Ok(account) => Some(account),
+            Err(e) => {
+                eprintln!("Failed to parse {ACCOUNT_FILE}: {e}");
+
+                None
             }
         }
+    }
 
-        Some(ParsedDex {
-            header,
-            messages
+    pub async fn save(&self, db: &Path) -> anyhow::Result<()> {
+        fs::create_dir_all(db).await?;
+
+        // Read by export, which can't do much with half of one
+        replace_file(&db.join(ACCOUNT_FILE), serde_json::to_string_pretty(self)?.as_bytes()).await?;
+
+        Ok(())
+    }
+}
+
+/// The `.dex` layout this build writes. Files from before there was a version are version 0,
+/// where the header line is just the channel
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The first line of a `.dex`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Header {
+    /// The layout version, `FORMAT_VERSION` when it was written
+    pub dex: u32,
+    /// The dexporter that last rewrote the file
+    pub dexporter: Option<String>,
+    /// When the archive was started. Unknown for ones from before version 1
+    pub created_at: Option<DateTime<Utc>>,
+    /// When an update last got to the end of the channel
+    pub synced_at: Option<DateTime<Utc>>,
+    /// The newest message as of that update
+    pub cursor: Option<Snowflake>,
+    pub channel: Channel,
+    /// Past names, topics and categories, oldest first
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub history: Vec<ChannelChange>
+}
+
+impl Header {
+    pub fn new(channel: Channel) -> Self {
+        Header {
+            dex: FORMAT_VERSION,
+            dexporter: Some(env!("CARGO_PKG_VERSION").to_string()),
+            created_at: Some(Utc::now()),
+            synced_at: None,
+            cursor: None,
+            channel,
+            history: vec![]
+        }
+    }
+
+    /// Reads a header line of any version
+    pub fn from_line(line: &[u8]) -> Result<Self, serde_json::Error> {
+        let value: serde_json::Value = serde_json::from_slice(line)?;
+
+        if value.get("dex").is_some() {
+            return serde_json::from_value(value);
+        }
+
+        Ok(Header {
+            dex: 0,
+            dexporter: None,
+            created_at: None,
+            synced_at: None,
+            cursor: None,
+            channel: serde_json::from_value(value)?,
+            history: vec![]
         })
     }
+}
 
-    pub async fn save(&self, file_path: &Path)  -> anyhow::Result<()> {
-        let file = tokio::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(file_path)
-            .await?;
-        let mut file = BufWriter::new(file);
+/// A change to a `.dex` that can't be expressed by appending a message line. Written after the
+/// messages, and replayed over them in order when the file is parsed
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "journal", rename_all = "snake_case")]
+pub enum Journal {
+    /// Replaces the channel in the header
+    Header { channel: Channel },
+    /// Replaces the stored copy of an edited message, revisions and all
+    Edit { message: Message },
+    Delete { id: Snowflake, deleted_at: DateTime<Utc> },
+    /// Drops a message outright. Only used for imported placeholders that got replaced by the real one
+    Remove { id: Snowflake },
+    /// Something about the channel changed. Comes before the `Header` with the channel as it is now
+    Changed { change: ChannelChange },
+    /// An update got to the end of the channel
+    Synced { synced_at: DateTime<Utc>, cursor: Option<Snowflake> }
+}
 
-        file.write_all(serde_json::to_string(&self.header)?.as_bytes()).await?;
-        file.write_all(b"\n").await?;
+// Journal lines always start with their tag, since that's how serde writes internally tagged enums.
+// Saves us trying to parse every message line as a journal record first
+pub const JOURNAL_PREFIX: &str = "{\"journal\":";
 
-        for message in self.messages.iter() {
-            file.write_all(serde_json::to_string(&message).unwrap().as_bytes()).await?;
-            file.write_all(b"\n").await?;
+#[derive(Debug)]
+enum Pending {
+    Message(Message),
+    Journal(Journal)
+}
+
+/// Why a `.dex` couldn't be read. Line numbers count from 1, the header being line 1
+#[derive(Debug)]
+pub enum DexError {
+    Io { path: PathBuf, error: io::Error },
+    /// Zero bytes. Older versions created these just by looking for a file
+    Empty { path: PathBuf },
+    Header { path: PathBuf, error: serde_json::Error },
+    /// Written by a newer dexporter, in a layout we don't know
+    Version { path: PathBuf, version: u32 },
+    Line { path: PathBuf, line: usize, error: String },
+    /// A frame of a compressed archive, a save's worth of lines, that can't be decompressed
+    Frame { path: PathBuf, offset: u64, error: String }
+}
+
+impl DexError {
+    /// Whether there's just no archive there yet, as opposed to a broken one
+    pub fn is_missing(&self) -> bool {
+        match self {
+            DexError::Io { error, .. } => error.kind() == io::ErrorKind::NotFound,
+            DexError::Empty { .. } => true,
+            _ => false
         }
+    }
+}
 
-        Ok(())
+impl fmt::Display for DexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DexError::Io { path, error } => write!(f, "{}: {error}", path.display()),
+            DexError::Empty { path } => write!(f, "{}: empty file", path.display()),
+            DexError::Header { path, error } => write!(f, "{}:1: bad header: {error}", path.display()),
+            DexError::Version { path, version } => write!(f, "{}: format version {version} is newer than this dexporter knows ({FORMAT_VERSION}), update it", path.display()),
+            DexError::Line { path, line, error } => write!(f, "{}:{line}: {error} (--salvage keeps going, and quarantines bad lines)", path.display()),
+            DexError::Frame { path, offset, error } => write!(f, "{}: the save at byte {offset} is corrupt: {error} (--salvage keeps going without it)", path.display())
+        }
     }
 }
 
-async fn update_channel(options: &Update, channel: &Channel, file_path: &Path) -> anyhow::Result<()> {
-    let mut parsed = ParsedDex::parse(file_path).await.unwrap_or_else(|| ParsedDex {
-        header: channel.clone(),
-        messages: imbl::vector![]
-    });
+impl std::error::Error for DexError {}
 
-    // Messages are ordered oldest to newest - start from last, newest message's id
-    let mut start_from = parsed.messages.last().map_or(0, |m| *m.id);
+/// How to fix up the end of a file before appending to it
+#[derive(Debug, Clone, Copy)]
+enum TailRepair {
+    /// The last line was cut off partway through, likely by a crash while appending. It never made it,
+    /// so it's cut off at this length
+    Truncate(u64),
+    /// The last line is fine, it's just missing its newline
+    Newline
+}
 
-    if channel.last_message_id() == Some(start_from) {
-        eprintln!("skipping: {}; manifest states last message id is the same as stored", channel.display());
-        return Ok(());
+const DEX_EXTENSION: &str = "dex";
+const COMPRESSED_EXTENSION: &str = "zst";
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// Whether `path` is a `.dex.zst`. Those are a run of zstd frames, one per save, that decompress to a `.dex`,
+/// so saving can still append instead of rewriting the whole file
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension().is_some_and(|e| e == COMPRESSED_EXTENSION)
+}
+
+/// Whether `path` is an archive, compressed or not
+pub fn is_dex(path: &Path) -> bool {
+    let path = if is_compressed(path) { Path::new(path.file_stem().unwrap_or_default()) } else { path };
+
+    path.extension().is_some_and(|e| e == DEX_EXTENSION)
+}
+
+/// The id of the channel in an archive, going by its file name
+pub fn dex_id(path: &Path) -> Option<&str> {
+    path.file_name()?.to_str()?.split('.').next()
+}
+
+/// The compressed or uncompressed counterpart of an archive's path
+pub fn toggle_compressed(path: &Path) -> PathBuf {
+    if is_compressed(path) {
+        path.with_extension("")
+    } else {
+        let mut compressed = path.as_os_str().to_owned();
+        compressed.push(".");
+        compressed.push(COMPRESSED_EXTENSION);
+
+        PathBuf::from(compressed)
+    }
+}
+
+/// Whichever of `path`, a `.dex`, and its `.dex.zst` is there. If neither is, the one to start, depending on `compress`
+pub fn pick_dex(path: PathBuf, compress: bool) -> PathBuf {
+    let compressed = toggle_compressed(&path);
+
+    if compressed.exists() || (compress && !path.exists()) {
+        compressed
+    } else {
+        path
     }
+}
 
-    for i in 1u32.. {
-        eprintln!("fetching: {} length: {} from: {}", channel.display(), parsed.messages.len(), start_from);
+/// An archive, read a line at a time and decompressed on the way if need be
+pub struct DexLines {
+    reader: BufReader<Source>,
+    // Where the next line starts in the decompressed contents
+    offset: u64
+}
 
-        let messages = fetch_messages(&options.token, channel.id(), start_from).await?;
+enum Source {
+    Plain(std::fs::File),
+    Compressed(Frames)
+}
 
-        if messages.is_empty() {
-            break;
+impl DexLines {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::open_until(path, u64::MAX)
+    }
+
+    /// Only reads the frames of a compressed archive that start before `until`
+    fn open_until(path: &Path, until: u64) -> io::Result<Self> {
+        let file = std::fs::File::open(path)?;
+
+        let source = if is_compressed(path) {
+            Source::Compressed(Frames { path: path.to_path_buf(), input: Some(BufReader::new(file)), decoder: None, start: 0, until, torn: None })
+        } else {
+            Source::Plain(file)
+        };
+
+        Ok(DexLines { reader: BufReader::new(source), offset: 0 })
+    }
+
+    /// Reads the next line into `line`, without its newline. Returns where it starts and whether it had a newline,
+    /// which only the last one can be missing, or `None` at the end. A compressed archive's frame that can't be
+    /// decompressed is a `CorruptFrame` error, with what was read of the line left in `line`
+    pub fn next_line(&mut self, line: &mut Vec<u8>) -> io::Result<Option<(u64, bool)>> {
+        line.clear();
+
+        let read = self.reader.read_until(b'\n', line)?;
+
+        if read == 0 {
+            return Ok(None);
         }
 
-        // I don't wanna encode whether the first or last is the latest one
-        if let Some(first) = messages.first() {
-            start_from = start_from.max(*first.id);
+        let start = self.offset;
+        let terminated = line.last() == Some(&b'\n');
+
+        self.offset += read as u64;
+
+        if terminated {
+            line.pop();
         }
 
-        if let Some(last) = messages.last() {
-            start_from = start_from.max(*last.id);
+        Ok(Some((start, terminated)))
+    }
+
+    /// For a compressed archive, where its last frame starts if that was cut off, once the lines run out.
+    /// Lines from it are read like any others, up to where it's cut off
+    pub fn torn_frame(&self) -> Option<u64> {
+        match self.reader.get_ref() {
+            Source::Compressed(frames) => frames.torn,
+            Source::Plain(_) => None
         }
+    }
 
-        for message in messages {
-            let result = parsed.messages.binary_search_by_key(&message.timestamp, |m| m.timestamp);
+    /// Goes on from the frame after a corrupt one
+    pub fn skip_frame(&mut self) -> io::Result<()> {
+        match self.reader.get_mut() {
+            Source::Compressed(frames) => frames.skip(),
+            Source::Plain(_) => Ok(())
+        }
+    }
+}
 
-            match result {
-                Ok(index) => parsed.messages[index] = message,
-                Err(index) => parsed.messages.insert(index, message),
+impl io::Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Plain(file) => file.read(buf),
+            Source::Compressed(frames) => frames.read(buf)
+        }
+    }
+}
+
+/// A compressed archive's zstd frames, one per save, decompressed one after the other
+struct Frames {
+    path: PathBuf,
+    // Between frames, or `None` once there's nothing more to read
+    input: Option<BufReader<std::fs::File>>,
+    decoder: Option<zstd::stream::read::Decoder<'static, BufReader<std::fs::File>>>,
+    // Where the frame being read starts
+    start: u64,
+    until: u64,
+    torn: Option<u64>
+}
+
+impl Frames {
+    fn skip(&mut self) -> io::Result<()> {
+        self.decoder = None;
+        self.input = match next_frame(&self.path, self.start + 1)? {
+            Some(start) => {
+                let mut input = BufReader::new(std::fs::File::open(&self.path)?);
+
+                input.seek(io::SeekFrom::Start(start))?;
+
+                Some(input)
+            },
+            None => None
+        };
+
+        Ok(())
+    }
+}
+
+impl io::Read for Frames {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let decoder = match &mut self.decoder {
+                Some(decoder) => decoder,
+                None => {
+                    let Some(mut input) = self.input.take() else {
+                        return Ok(0);
+                    };
+
+                    self.start = input.stream_position()?;
+
+                    if self.start >= self.until || input.fill_buf()?.is_empty() {
+                        return Ok(0);
+                    }
+
+                    self.decoder.insert(zstd::stream::read::Decoder::with_buffer(input)?.single_frame())
+                }
+            };
+
+            match decoder.read(buf) {
+                Ok(0) => self.input = self.decoder.take().map(|decoder| decoder.finish()),
+                Ok(read) => return Ok(read),
+                // A crash partway through writing the last save. A frame that only looks cut off, since
+                // damage made it run on past the next one, is corrupt like any other
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && next_frame(&self.path, self.start + 1)?.is_none() => {
+                    self.decoder = None;
+                    self.torn = Some(self.start);
+
+                    return Ok(0);
+                },
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, CorruptFrame { offset: self.start, error: e.to_string() }))
             }
         }
+    }
+}
 
-        if i.is_multiple_of(100) {
-            eprintln!("storing: {}", channel.display());
-            parsed.save(file_path).await?;
+/// Where the first thing that looks like the start of a zstd frame is in `path`, from `from` on
+fn next_frame(path: &Path, from: u64) -> io::Result<Option<u64>> {
+    const MAGIC: [u8; 4] = 0xFD2FB528_u32.to_le_bytes();
+
+    let mut input = BufReader::new(std::fs::File::open(path)?);
+    let mut offset = input.seek(io::SeekFrom::Start(from))?;
+    let mut matched = 0;
+
+    loop {
+        let buf = input.fill_buf()?;
+
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        for (i, byte) in buf.iter().enumerate() {
+            matched = if *byte == MAGIC[matched] { matched + 1 } else { usize::from(*byte == MAGIC[0]) };
+
+            if matched == MAGIC.len() {
+                return Ok(Some(offset + i as u64 + 1 - MAGIC.len() as u64));
+            }
         }
+
+        let read = buf.len();
+
+        input.consume(read);
+        offset += read as u64;
     }
+}
 
-    parsed.save(file_path).await?;
+/// A frame of a compressed archive that can't be decompressed, and isn't just cut off at the end
+#[derive(Debug)]
+pub struct CorruptFrame {
+    /// Where it starts in the compressed file
+    pub offset: u64,
+    pub error: String
+}
 
-    Ok(())
+impl fmt::Display for CorruptFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the save at byte {} is corrupt: {}", self.offset, self.error)
+    }
 }
 
-pub async fn update_channels(options: &Update) {
-    let stream = stream::iter(&options.state.channels);
+impl std::error::Error for CorruptFrame {}
 
-    let concurrent = stream.for_each_concurrent(5, |channel| async move {
-        let mut path = std::env::current_dir().unwrap();
+impl CorruptFrame {
+    /// The `CorruptFrame` in an error from `DexLines`, if that's what it is
+    pub fn of(error: &io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+}
 
-        path.push("db");
+/// What to write to `path` for `lines`
+fn encode(path: &Path, lines: Vec<u8>) -> io::Result<Vec<u8>> {
+    if is_compressed(path) {
+        zstd::encode_all(&lines[..], COMPRESSION_LEVEL)
+    } else {
+        Ok(lines)
+    }
+}
 
-        let id = match channel {
-            Channel::DMChannel(c) => {
-                path.push("DMs");
+// The fewest messages a channel needs before gaps in it are worth looking at
+const MIN_MESSAGES_FOR_GAPS: usize = 200;
 
-                c.id.to_string()
-            },
-            Channel::TextChannel(c) => {
-                path.push(c.guild_id.to_string());
+/// Two consecutive messages in an archive with nothing stored between them
+#[derive(Debug, Clone, Copy)]
+pub struct Gap {
+    pub after: Snowflake,
+    pub before: Snowflake
+}
 
-                c.id.to_string()
-            },
+impl Gap {
+    pub fn duration(&self) -> TimeDelta {
+        self.before.timestamp() - self.after.timestamp()
+    }
+}
+
+#[derive(Debug)]
+pub struct ParsedDex {
+    pub header: Header,
+    pub messages: imbl::Vector<Message>,
+    // Lines to append on the next save
+    pending: Vec<Pending>,
+    // Whether the file has a header yet. If not, the next save writes it out in full
+    on_disk: bool,
+    tail: Option<TailRepair>,
+    // Lines --salvage skipped over, with their line numbers. Moved to the quarantine file on compaction,
+    // which is when they'd otherwise be lost
+    quarantined: Vec<(usize, Vec<u8>)>,
+    // --salvage skipped over a corrupt frame of a compressed archive, so it's rewritten without it on the next save
+    damaged: bool,
+    // The .dex.idx, if the archive has one. Kept up to date on save
+    index: Option<DexIndex>,
+    // Only some of the messages were loaded, see `parse_range`
+    partial: bool
+}
+
+impl ParsedDex {
+    pub fn new(channel: Channel) -> Self {
+        ParsedDex {
+            header: Header::new(channel),
+            messages: imbl::vector![],
+            pending: vec![],
+            on_disk: false,
+            tail: None,
+            quarantined: vec![],
+            damaged: false,
+            index: None,
+            partial: false
+        }
+    }
+
+    /// An archive read from somewhere other than a `.dex`, which has nothing pending. `partial` if it's
+    /// only some of the channel's messages
+    pub fn from_parts(header: Header, messages: imbl::Vector<Message>, partial: bool) -> Self {
+        ParsedDex {
+            header,
+            messages,
+            pending: vec![],
+            on_disk: true,
+            tail: None,
+            quarantined: vec![],
+            damaged: false,
+            index: None,
+            partial
+        }
+    }
+
+    /// Reads a `.dex` without touching it. Any bad line is an error, unless `salvage` is set,
+    /// in which case they're set aside for the quarantine file and parsing goes on
+    pub async fn parse(file_path: &Path, salvage: bool) -> Result<Self, DexError> {
+        let (parsed, torn_frame) = Self::read(file_path, salvage, u64::MAX)?;
+
+        let Some(start) = torn_frame else {
+            return Ok(parsed);
         };
 
-        fs::create_dir_all(&path).await.unwrap();
+        eprintln!("{}: last save was cut off, dropping it", file_path.display());
 
-        path.push(format!("{id}.dex"));
+        // What was read of it is in there too, so again without it. Only ever after a crash
+        let (mut parsed, _) = Self::read(file_path, salvage, start)?;
 
-        match update_channel(options, channel, &path).await {
-            Ok(_) => {},
-            Err(e) => {
-                eprintln!("update channel: {} failed: {}", channel.display(), e);
-            },
+        parsed.tail = Some(TailRepair::Truncate(start));
+
+        Ok(parsed)
+    }
+
+    /// `parse`, up to the frame of a compressed archive at `until`. Also returns where the last frame starts,
+    /// if it's cut off
+    fn read(file_path: &Path, salvage: bool, until: u64) -> Result<(Self, Option<u64>), DexError> {
+        let io_error = |error: io::Error| match CorruptFrame::of(&error) {
+            Some(corrupt) => DexError::Frame { path: file_path.to_path_buf(), offset: corrupt.offset, error: corrupt.error.clone() },
+            None => DexError::Io { path: file_path.to_path_buf(), error }
         };
-    });
 
-    concurrent.await;
+        let mut lines = DexLines::open_until(file_path, until).map_err(io_error)?;
+        let compressed = is_compressed(file_path);
+        let mut line = vec![];
+
+        if lines.next_line(&mut line).map_err(io_error)?.is_none() {
+            return Err(DexError::Empty { path: file_path.to_path_buf() });
+        }
+
+        let header = Header::from_line(&line)
+            .map_err(|error| DexError::Header { path: file_path.to_path_buf(), error })?;
+
+        if header.dex > FORMAT_VERSION {
+            return Err(DexError::Version { path: file_path.to_path_buf(), version: header.dex });
+        }
+
+        let mut parsed = ParsedDex {
+            header,
+            messages: imbl::vector![],
+            pending: vec![],
+            on_disk: true,
+            tail: None,
+            quarantined: vec![],
+            damaged: false,
+            index: if compressed { None } else { DexIndex::load(file_path) },
+            partial: false
+        };
+
+        let mut line_number = 1;
+
+        loop {
+            let (start, terminated) = match lines.next_line(&mut line) {
+                Ok(Some(read)) => read,
+                Ok(None) => break,
+                Err(ref e) if salvage && let Some(corrupt) = CorruptFrame::of(e) => {
+                    eprintln!("{}: {corrupt}, skipping the rest of it", file_path.display());
+
+                    line_number += 1;
+
+                    // Whatever of the line it was in the middle of came out
+                    if !line.is_empty() {
+                        parsed.quarantined.push((line_number, line.clone()));
+                    }
+
+                    parsed.damaged = true;
+
+                    lines.skip_frame().map_err(io_error)?;
+
+                    continue;
+                },
+                Err(e) => return Err(io_error(e))
+            };
+
+            line_number += 1;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let result = parsed.read_line(&line);
+
+            // Every frame of a compressed file ends in a newline, what's cut off there is whole frames
+            let unterminated = !terminated && !compressed;
+
+            match result {
+                Ok(()) if unterminated => parsed.tail = Some(TailRepair::Newline),
+                Ok(()) => {},
+                Err(e) if unterminated => {
+                    eprintln!("{}:{line_number}: last line was cut off ({e}), dropping it", file_path.display());
+
+                    parsed.tail = Some(TailRepair::Truncate(start));
+
+                    if salvage {
+                        parsed.quarantined.push((line_number, line.clone()));
+                    }
+                },
+                Err(e) if salvage => {
+                    // Only moved to the quarantine file if the archive gets saved, which says so
+                    eprintln!("{}:{line_number}: skipping: {e}", file_path.display());
+
+                    parsed.quarantined.push((line_number, line.clone()));
+                },
+                Err(e) => {
+                    return Err(DexError::Line { path: file_path.to_path_buf(), line: line_number, error: e.to_string() });
+                }
+            }
+        }
+
+        Ok((parsed, lines.torn_frame()))
+    }
+
+    /// Reads only what's needed to get the messages in `ids` right, if the archive has an index. Otherwise
+    /// reads it all, and drops the rest. Either way, what's left can be added to and saved, but not compacted.
+    /// `salvage` is as for `parse`, which is what bad lines fall back to
+    pub async fn parse_range(file_path: &Path, ids: RangeInclusive<Snowflake>, salvage: bool) -> Result<Self, DexError> {
+        let io_error = |error| DexError::Io { path: file_path.to_path_buf(), error };
+
+        let index = if is_compressed(file_path) { None } else { DexIndex::load(file_path) };
+
+        let Some(index) = index else {
+            let mut parsed = Self::parse(file_path, salvage).await?;
+
+            parsed.keep_only(&ids);
+
+            return Ok(parsed);
+        };
+
+        let mut header_line = vec![];
+
+        BufReader::new(std::fs::File::open(file_path).map_err(io_error)?)
+            .read_until(b'\n', &mut header_line)
+            .map_err(io_error)?;
+
+        if header_line.is_empty() {
+            return Err(DexError::Empty { path: file_path.to_path_buf() });
+        }
+
+        let header = Header::from_line(header_line.strip_suffix(b"\n").unwrap_or(&header_line))
+            .map_err(|error| DexError::Header { path: file_path.to_path_buf(), error })?;
+
+        if header.dex > FORMAT_VERSION {
+            return Err(DexError::Version { path: file_path.to_path_buf(), version: header.dex });
+        }
+
+        let file_len = std::fs::metadata(file_path).map_err(io_error)?.len();
+        let contents = read_regions(file_path, index.regions(&ids, file_len)).map_err(io_error)?;
+
+        let mut parsed = ParsedDex {
+            header,
+            messages: imbl::vector![],
+            pending: vec![],
+            on_disk: true,
+            tail: None,
+            quarantined: vec![],
+            damaged: false,
+            index: Some(index),
+            partial: false
+        };
+
+        for line in contents.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            if parsed.read_line(line).is_err() {
+                // Let the full parse say where exactly, and deal with cut off lines
+                let mut parsed = Self::parse(file_path, salvage).await?;
+
+                parsed.keep_only(&ids);
+
+                return Ok(parsed);
+            }
+        }
+
+        parsed.keep_only(&ids);
+
+        Ok(parsed)
+    }
+
+    fn keep_only(&mut self, ids: &RangeInclusive<Snowflake>) {
+        // Not retain, imbl's swaps the wrong elements once a message was inserted in front of a full chunk
+        self.messages = self.messages.iter().filter(|m| ids.contains(&m.id)).cloned().collect();
+        self.partial = true;
+    }
+
+    fn read_line(&mut self, line: &[u8]) -> Result<(), serde_json::Error> {
+        if line.starts_with(JOURNAL_PREFIX.as_bytes()) {
+            serde_json::from_slice::<Journal>(line).map(|journal| self.replay(journal))
+        } else {
+            serde_json::from_slice::<Message>(line).map(|mut message| {
+                // Older archives had their imports at id 0
+                message.assign_synthetic_id();
+
+                self.load(message);
+            })
+        }
+    }
+
+    /// Places a message read from disk. They're usually already in order, but appended backfills and
+    /// older archives, which were kept in timestamp order, aren't
+    fn load(&mut self, message: Message) {
+        if self.messages.last().is_none_or(|last| last.id < message.id) {
+            self.messages.push_back(message);
+
+            return;
+        }
+
+        match self.messages.binary_search_by_key(&message.id, |m| m.id) {
+            Ok(index) => self.messages[index] = message,
+            Err(index) => self.messages.insert(index, message),
+        }
+    }
+
+    fn replay(&mut self, journal: Journal) {
+        match journal {
+            Journal::Header { channel } => self.header.channel = channel,
+            Journal::Edit { message } => self.load(message),
+            Journal::Delete { id, deleted_at } => {
+                if let Ok(index) = self.messages.binary_search_by_key(&id, |m| m.id) {
+                    self.messages[index].deleted_at = Some(deleted_at);
+                }
+            },
+            Journal::Remove { id } => {
+                if let Ok(index) = self.messages.binary_search_by_key(&id, |m| m.id) {
+                    self.messages.remove(index);
+                }
+            },
+            Journal::Changed { change } => self.header.history.push(change),
+            Journal::Synced { synced_at, cursor } => {
+                self.header.synced_at = Some(synced_at);
+                self.header.cursor = cursor;
+            }
+        }
+    }
+
+    /// Appends whatever changed since the last save. Writes the whole file if it's new
+    pub async fn save(&mut self, file_path: &Path) -> anyhow::Result<()> {
+        // Salvaged files get rewritten without their bad lines, so they parse cleanly from then on
+        if !self.on_disk || !self.quarantined.is_empty() || self.damaged {
+            self.pending.clear();
+
+            return self.compact(file_path).await;
+        }
+
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut lines = vec![];
+
+        for pending in self.pending.iter() {
+            match pending {
+                Pending::Message(message) => serde_json::to_writer(&mut lines, message)?,
+                Pending::Journal(journal) => serde_json::to_writer(&mut lines, journal)?,
+            };
+
+            lines.push(b'\n');
+        }
+
+        // Compressed files get a frame per save
+        let bytes = encode(file_path, lines)?;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(file_path)
+            .await?;
+
+        match self.tail.take() {
+            Some(TailRepair::Truncate(length)) => file.set_len(length).await?,
+            Some(TailRepair::Newline) => file.write_all(b"\n").await?,
+            None => {}
+        }
+
+        file.write_all(&bytes).await?;
+        file.sync_data().await?;
+
+        if let Some(index) = &mut self.index {
+            let file_len = file.metadata().await?.len();
+
+            // A cut off line that was dropped had made it into the index somehow, start over
+            if index.len > file_len {
+                *index = DexIndex::build(&std::fs::read(file_path)?);
+            } else {
+                index.extend(&read_regions(file_path, std::iter::once(index.len..file_len))?);
+            }
+
+            index.save(file_path).await?;
+        }
+
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Rewrites the whole file in canonical form: the header, then every message in id order, no journal.
+    /// See `replace_file` for how it survives crashes
+    pub async fn compact(&mut self, file_path: &Path) -> anyhow::Result<()> {
+        if self.partial {
+            return Err(anyhow::anyhow!("{} was only partly read, rewriting it would lose the rest", file_path.display()));
+        }
+
+        // Whatever version it was read as, it's written out as this one
+        self.header.dex = FORMAT_VERSION;
+        self.header.dexporter = Some(env!("CARGO_PKG_VERSION").to_string());
+
+        let mut lines = vec![];
+
+        serde_json::to_writer(&mut lines, &self.header)?;
+        lines.push(b'\n');
+
+        for message in self.messages.iter() {
+            serde_json::to_writer(&mut lines, &message)?;
+            lines.push(b'\n');
+        }
+
+        // Offsets are all different now. New archives start out with one
+        if self.index.is_some() || !self.on_disk {
+            self.index = (!is_compressed(file_path)).then(|| DexIndex::build(&lines));
+        }
+
+        let bytes = encode(file_path, lines)?;
+
+        // Before the lines are gone from the archive
+        self.quarantine(file_path).await?;
+
+        // What couldn't be read of a corrupt frame is only in the file as it is
+        if self.damaged {
+            let mut damaged_path = file_path.as_os_str().to_owned();
+            damaged_path.push(".damaged");
+
+            fs::copy(file_path, &damaged_path).await?;
+
+            eprintln!("kept the damaged archive as {}", PathBuf::from(damaged_path).display());
+        }
+
+        replace_file(file_path, &bytes).await?;
+
+        if let Some(index) = &mut self.index {
+            index.save(file_path).await?;
+        }
+
+        self.pending.clear();
+        self.on_disk = true;
+        self.tail = None;
+        self.damaged = false;
+
+        Ok(())
+    }
+
+    /// Stretches between consecutive messages that are long enough, compared to how busy the channel
+    /// usually is, that a page of messages was likely missed there. `factor` is how many typical gaps
+    /// one has to span to count, and it has to be at least `min_gap` long too, so busy channels going
+    /// quiet overnight aren't reported
+    pub fn suspicious_gaps(&self, factor: u32, min_gap: TimeDelta) -> Vec<Gap> {
+        // Too few messages to say what's typical
+        if self.messages.len() < MIN_MESSAGES_FOR_GAPS {
+            return vec![];
+        }
+
+        let mut deltas: Vec<TimeDelta> = self.messages.iter()
+            .zip(self.messages.iter().skip(1))
+            .map(|(a, b)| b.id.timestamp() - a.id.timestamp())
+            .collect();
+
+        deltas.sort();
+
+        let typical = deltas[deltas.len() / 2].max(TimeDelta::seconds(1));
+        let threshold = (typical * factor as i32).max(min_gap);
+
+        self.messages.iter()
+            .zip(self.messages.iter().skip(1))
+            .filter(|(a, b)| b.id.timestamp() - a.id.timestamp() > threshold)
+            .map(|(a, b)| Gap { after: a.id, before: b.id })
+            .collect()
+    }
+
+    /// Keeps lines --salvage couldn't read in `<file>.quarantine` before compaction drops them,
+    /// each after a comment with the line number it had
+    pub async fn quarantine(&mut self, file_path: &Path) -> anyhow::Result<()> {
+        if self.quarantined.is_empty() {
+            return Ok(());
+        }
+
+        let mut quarantine_path = file_path.as_os_str().to_owned();
+        quarantine_path.push(".quarantine");
+
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&quarantine_path)
+            .await?;
+        let mut file = BufWriter::new(file);
+
+        for (line_number, line) in &self.quarantined {
+            file.write_all(format!("# line {line_number}, {}\n", Utc::now().to_rfc3339()).as_bytes()).await?;
+            file.write_all(line).await?;
+            file.write_all(b"\n").await?;
+        }
+
+        file.flush().await?;
+        file.get_ref().sync_all().await?;
+
+        eprintln!("moved {} unreadable lines to {}", self.quarantined.len(), PathBuf::from(quarantine_path).display());
+
+        self.quarantined.clear();
+
+        Ok(())
+    }
+
+    /// Swaps in the channel as Discord has it now, keeping track of its old name, topic and category
+    pub fn refresh_header(&mut self, channel: &Channel) {
+        let mut fresh = channel.clone();
+
+        // Ours, built from messages. Discord doesn't send it
+        if let (Channel::DMChannel(fresh), Channel::DMChannel(old)) = (&mut fresh, &self.header.channel) {
+            fresh.membership = old.membership.clone();
+        }
+
+        if fresh.same_as(&self.header.channel) {
+            return;
+        }
+
+        let seen_at = Utc::now();
+
+        for field in fresh.changes_from(&self.header.channel) {
+            let change = ChannelChange { seen_at, field };
+
+            eprintln!("changed: {}; {}", fresh.display(), change.field.describe());
+
+            self.header.history.push(change.clone());
+            self.journal(Journal::Changed { change });
+        }
+
+        self.header.channel = fresh;
+        self.journal(Journal::Header { channel: self.header.channel.clone() });
+    }
+
+    /// Notes that an update just got to the end of the channel
+    pub fn synced(&mut self) {
+        let synced_at = Utc::now();
+        let cursor = self.messages.last().map(|m| m.id);
+
+        let moved = cursor != self.header.cursor;
+
+        self.header.synced_at = Some(synced_at);
+        self.header.cursor = cursor;
+
+        // Not worth a line of its own on every run that found nothing. It goes out with whatever else
+        // is written, or with the header when the file is next rewritten
+        if moved || !self.pending.is_empty() {
+            self.journal(Journal::Synced { synced_at, cursor });
+        }
+    }
+
+    /// The ids of the messages changed since the last save. For stores that save messages as they are now,
+    /// instead of what happened to them
+    pub fn touched(&self) -> Vec<Snowflake> {
+        self.pending.iter()
+            .filter_map(|pending| match pending {
+                Pending::Message(message) | Pending::Journal(Journal::Edit { message }) => Some(message.id),
+                Pending::Journal(Journal::Delete { id, .. } | Journal::Remove { id }) => Some(*id),
+                // All in the header, which is saved regardless
+                Pending::Journal(Journal::Header { .. } | Journal::Changed { .. } | Journal::Synced { .. }) => None
+            })
+            .collect()
+    }
+
+    /// Forgets what was pending, once another store has saved it
+    pub fn mark_saved(&mut self) {
+        self.pending.clear();
+    }
+
+    fn journal(&mut self, journal: Journal) {
+        self.pending.push(Pending::Journal(journal));
+    }
+
+    fn merge(&mut self, messages: Vec<Message>) {
+        let mut header_changed = false;
+
+        for message in messages {
+            if let Channel::DMChannel(dm) = &mut self.header.channel && let Some(change) = message.membership_change() {
+                header_changed |= dm.record_membership(change);
+            }
+
+            self.reconcile(&message);
+            self.insert(message);
+        }
+
+        if header_changed {
+            self.journal(Journal::Header { channel: self.header.channel.clone() });
+        }
+    }
+
+    fn position(&self, id: Snowflake) -> usize {
+        match self.messages.binary_search_by_key(&id, |m| m.id) {
+            Ok(index) => index,
+            Err(index) => index,
+        }
+    }
+
+    /// Indices of the messages created from `start` up to `end`, both inclusive, to the second
+    pub fn between(&self, start: DateTime<Utc>, end: DateTime<Utc>) -> Range<usize> {
+        let start = self.position(Snowflake::from_datetime(start.trunc_subsecs(0)));
+        let end = self.position(Snowflake::from_datetime(end.trunc_subsecs(0) + TimeDelta::seconds(1)));
+
+        start..end
+    }
+
+    /// Inserts a message in id order, or replaces the stored copy with the same id.
+    /// Replacements only make it to disk if they visibly changed the message
+    pub fn insert(&mut self, mut message: Message) {
+        match self.messages.binary_search_by_key(&message.id, |m| m.id) {
+            Ok(index) => {
+                let old = &self.messages[index];
+                // Back after it was marked deleted, which a rescan does for messages it couldn't see at the time
+                let edited = old.differs_from(&message) || old.deleted_at != message.deleted_at;
+
+                message.supersede(&self.messages[index]);
+
+                if edited {
+                    self.journal(Journal::Edit { message: message.clone() });
+                }
+
+                self.messages[index] = message;
+            },
+            Err(index) => {
+                self.pending.push(Pending::Message(message.clone()));
+                self.messages.insert(index, message);
+            },
+        }
+    }
+
+    /// Marks a message as deleted, keeping it around
+    pub fn mark_deleted(&mut self, id: Snowflake, deleted_at: DateTime<Utc>) {
+        if let Ok(index) = self.messages.binary_search_by_key(&id, |m| m.id) {
+            self.messages[index].deleted_at = Some(deleted_at);
+
+            self.journal(Journal::Delete { id, deleted_at });
+        }
+    }
+
+    /// Drops imported placeholders for a message we now have the real thing of
+    fn reconcile(&mut self, message: &Message) {
+        let Some(timestamp) = message.timestamp.filter(|_| !message.synthetic) else {
+            return;
+        };
+
+        let placeholders: Vec<_> = self.between(timestamp, timestamp)
+            .filter(|&index| self.messages[index].is_placeholder_for(message))
+            .collect();
+
+        for index in placeholders.into_iter().rev() {
+            let removed = self.messages.remove(index);
+
+            self.journal(Journal::Remove { id: removed.id });
+        }
+    }
+}
+
+/// Where a channel's archive lives in the db: `DMs/<id>.dex` for DMs, `<guild id>/<id>.dex` for guild channels,
+/// and `<guild id>/<parent id>/<id>.dex` for threads
+pub fn dex_path(db: &Path, channel: &Channel) -> PathBuf {
+    let mut path = db.to_path_buf();
+
+    match channel {
+        Channel::DMChannel(_) => path.push("DMs"),
+        Channel::TextChannel(c) => {
+            path.push(c.guild_id.to_string());
+
+            // Threads live in a directory named after the channel they were started in
+            if channel.is_thread() && let Some(parent_id) = c.parent_id {
+                path.push(parent_id.to_string());
+            }
+        }
+    }
+
+    path.push(format!("{}.{DEX_EXTENSION}", channel.id()));
+
+    path
+}
+
+/// How far an update of a single channel got
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Complete,
+    /// Nothing new according to the channel's last message id
+    Unchanged,
+    /// Stopped by Ctrl-C. What was fetched is saved, the next update picks up from there
+    Partial,
+    /// Ctrl-C came before we got to it
+    NotStarted,
+    Skipped,
+    Failed
+}
+
+async fn update_channel(options: &Update, client: &DiscordClient, shutdown: &Shutdown, channel: &Channel, archive: &Archive<'_>) -> anyhow::Result<Outcome> {
+    let mut parsed = match archive.read(options.salvage).await? {
+        Some(mut parsed) => {
+            parsed.refresh_header(channel);
+
+            parsed
+        },
+        None => ParsedDex::new(channel.clone())
+    };
+
+    let result = if options.rescan {
+        rescan_channel(options, client, shutdown, channel, &mut parsed, archive).await
+    } else if options.repair {
+        repair_channel(options, client, shutdown, channel, &mut parsed, archive).await
+    } else {
+        fetch_new_messages(options, client, shutdown, channel, &mut parsed, archive).await
+    };
+
+    // Repairs and --since/--until runs don't necessarily see the newest messages
+    let whole_channel = !options.repair && options.since.is_none() && options.until.is_none();
+
+    if whole_channel && matches!(result, Ok(Outcome::Complete | Outcome::Unchanged)) {
+        parsed.synced();
+    }
+
+    // Keep whatever we got, even if the channel errored partway through
+    archive.save(&mut parsed).await?;
+
+    result
+}
+
+async fn fetch_new_messages(options: &Update, client: &DiscordClient, shutdown: &Shutdown, channel: &Channel, parsed: &mut ParsedDex, archive: &Archive<'_>) -> anyhow::Result<Outcome> {
+    let bounded = options.since.is_some() || options.until.is_some();
+    let since = options.since.map_or(0, |s| *s);
+    let until = options.until.map_or(u64::MAX, |u| *u);
+
+    // Messages are ordered oldest to newest - start from last, newest message's id
+    let newest = parsed.messages.last().map(|m| *m.id);
+    let oldest = parsed.messages.front().map(|m| *m.id);
+    // Not from --since if what's stored ends before it, that would leave a hole nothing comes back for
+    let mut start_from = newest.unwrap_or(since);
+
+    if !bounded && channel.last_message_id() == Some(start_from) {
+        eprintln!("skipping: {}; manifest states last message id is the same as stored", channel.display());
+        return Ok(Outcome::Unchanged);
+    }
+
+    let mut pages = 0u32;
+
+    // Walk forward from whatever we have, or from --since if there's nothing
+    while start_from < until {
+        if shutdown.requested() {
+            return Ok(Outcome::Partial);
+        }
+
+        eprintln!("fetching: {} length: {} from: {:#}", channel.display(), parsed.messages.len(), Snowflake(start_from));
+
+        let messages = client.fetch_messages(channel.id(), Page::After(start_from)).await?;
+
+        if messages.is_empty() {
+            break;
+        }
+
+        // I don't wanna encode whether the first or last is the latest one
+        start_from = start_from.max(messages.iter().map(|m| *m.id).max().unwrap_or(0));
+
+        parsed.merge(messages.into_iter().filter(|m| *m.id <= until).collect());
+
+        pages += 1;
+
+        if pages.is_multiple_of(100) {
+            eprintln!("storing: {}", channel.display());
+            archive.save(parsed).await?;
+        }
+    }
+
+    // Backfill anything between --since and the oldest stored message. The forward pass already
+    // covered the range for archives that started out empty
+    if bounded && let Some(oldest) = oldest {
+        let mut end_at = oldest.min(until.saturating_add(1));
+
+        while end_at > since {
+            if shutdown.requested() {
+                return Ok(Outcome::Partial);
+            }
+
+            eprintln!("backfilling: {} length: {} before: {:#}", channel.display(), parsed.messages.len(), Snowflake(end_at));
+
+            let messages = client.fetch_messages(channel.id(), Page::Before(end_at)).await?;
+
+            if messages.is_empty() {
+                break;
+            }
+
+            end_at = end_at.min(messages.iter().map(|m| *m.id).min().unwrap_or(0));
+
+            parsed.merge(messages.into_iter().filter(|m| *m.id >= since).collect());
+
+            pages += 1;
+
+            if pages.is_multiple_of(100) {
+                eprintln!("storing: {}", channel.display());
+                archive.save(parsed).await?;
+            }
+        }
+    }
+
+    Ok(Outcome::Complete)
+}
+
+/// Refetches only the stretches between stored messages that look like missed pages, see `ParsedDex::suspicious_gaps`
+async fn repair_channel(options: &Update, client: &DiscordClient, shutdown: &Shutdown, channel: &Channel, parsed: &mut ParsedDex, archive: &Archive<'_>) -> anyhow::Result<Outcome> {
+    let since = options.since.map_or(0, |s| *s);
+    let until = options.until.map_or(u64::MAX, |u| *u);
+
+    let gaps: Vec<_> = parsed.suspicious_gaps(options.gaps.gap_factor, TimeDelta::hours(options.gaps.min_gap_hours))
+        .into_iter()
+        .filter(|gap| *gap.before > since && *gap.after < until)
+        .collect();
+
+    if gaps.is_empty() {
+        eprintln!("skipping: {}; no suspicious gaps", channel.display());
+
+        return Ok(Outcome::Unchanged);
+    }
+
+    let length = parsed.messages.len();
+    let mut failed = 0;
+
+    for gap in &gaps {
+        let mut start_from = *gap.after;
+
+        // Page forward from the message before the gap until we're past the one after it
+        while start_from < *gap.before {
+            if shutdown.requested() {
+                return Ok(Outcome::Partial);
+            }
+
+            eprintln!("repairing: {} from: {:#} to: {:#}", channel.display(), Snowflake(start_from), gap.before);
+
+            let messages = match client.fetch_messages(channel.id(), Page::After(start_from)).await {
+                Ok(messages) => messages,
+                // Give up on this gap, but not the others
+                Err(e) if e.is_transient() => {
+                    eprintln!("couldn't repair: {} from: {:#}; {e}", channel.display(), gap.after);
+
+                    failed += 1;
+
+                    break;
+                },
+                Err(e) => return Err(e.into())
+            };
+
+            if messages.is_empty() {
+                break;
+            }
+
+            start_from = start_from.max(messages.iter().map(|m| *m.id).max().unwrap_or(0));
+
+            parsed.merge(messages.into_iter().filter(|m| m.id < gap.before).collect());
+        }
+
+        archive.save(parsed).await?;
+    }
+
+    eprintln!(
+        "repaired: {}; {} messages recovered from {} gaps",
+        channel.display(), parsed.messages.len() - length, gaps.len() - failed
+    );
+
+    Ok(if failed > 0 { Outcome::Failed } else { Outcome::Complete })
+}
+
+/// Walks all of `--since..=--until` again, merging in whatever changed and marking what's gone
+async fn rescan_channel(options: &Update, client: &DiscordClient, shutdown: &Shutdown, channel: &Channel, parsed: &mut ParsedDex, archive: &Archive<'_>) -> anyhow::Result<Outcome> {
+    let since = options.since.map_or(0, |s| *s);
+    let until = options.until.map_or(u64::MAX, |u| *u);
+
+    let mut seen = HashSet::new();
+    let mut start_from = since;
+    let mut pages = 0u32;
+
+    while start_from < until {
+        // Stopping early means we haven't seen everything, so we can't tell what's deleted
+        if shutdown.requested() {
+            return Ok(Outcome::Partial);
+        }
+
+        eprintln!("rescanning: {} from: {:#}", channel.display(), Snowflake(start_from));
+
+        let messages = client.fetch_messages(channel.id(), Page::After(start_from)).await?;
+
+        if messages.is_empty() {
+            break;
+        }
+
+        start_from = start_from.max(messages.iter().map(|m| *m.id).max().unwrap_or(0));
+
+        let messages: Vec<_> = messages.into_iter().filter(|m| *m.id <= until).collect();
+
+        seen.extend(messages.iter().map(|m| m.id));
+        parsed.merge(messages);
+
+        pages += 1;
+
+        if pages.is_multiple_of(100) {
+            eprintln!("storing: {}", channel.display());
+            archive.save(parsed).await?;
+        }
+    }
+
+    // Only got this far if the walk finished, so anything in range we didn't see is gone.
+    // Imported messages have no real id to check against, so they're left alone
+    let now = chrono::Utc::now();
+    let gone: Vec<_> = parsed.messages.iter()
+        .filter(|m| !m.synthetic && (since..=until).contains(&*m.id))
+        .filter(|m| m.deleted_at.is_none() && !seen.contains(&m.id))
+        .map(|m| m.id)
+        .collect();
+    let deleted = gone.len();
+
+    for id in gone {
+        parsed.mark_deleted(id, now);
+    }
+
+    eprintln!("rescanned: {}; {} messages seen, {} newly deleted", channel.display(), seen.len(), deleted);
+
+    Ok(Outcome::Complete)
+}
+
+pub async fn update_channels(options: &Update, client: &DiscordClient, shutdown: &Shutdown, archives: &Archives) {
+    // Forum and media channels are only selected for their posts, which are threads of their own
+    let stream = stream::iter(options.state.channels.iter().filter(|c| c.has_messages()));
+    let outcomes = Mutex::new(vec![]);
+
+    let concurrent = stream.for_each_concurrent(5, |channel| {
+        let outcomes = &outcomes;
+
+        async move {
+            if shutdown.requested() {
+                outcomes.lock().unwrap().push((channel.display(), Outcome::NotStarted));
+
+                return;
+            }
+
+            let archive = archives.archive(channel);
+
+            if let Archive::Dex(path) = &archive {
+                fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            }
+
+            let outcome = match update_channel(options, client, shutdown, channel, &archive).await {
+                Ok(outcome) => outcome,
+                Err(e) if matches!(e.downcast_ref(), Some(ApiError::Forbidden(_) | ApiError::NotFound(_))) => {
+                    eprintln!("skipping: {}; {}", channel.display(), e);
+
+                    Outcome::Skipped
+                },
+                Err(e) => {
+                    eprintln!("update channel: {} failed: {}", channel.display(), e);
+
+                    Outcome::Failed
+                },
+            };
+
+            outcomes.lock().unwrap().push((channel.display(), outcome));
+        }
+    });
+
+    concurrent.await;
+
+    let outcomes = outcomes.into_inner().unwrap();
+    let count = |outcome| outcomes.iter().filter(|(_, o)| *o == outcome).count();
+
+    eprintln!(
+        "done: {} complete, {} unchanged, {} skipped, {} failed",
+        count(Outcome::Complete), count(Outcome::Unchanged), count(Outcome::Skipped), count(Outcome::Failed)
+    );
+
+    if shutdown.requested() {
+        for (name, outcome) in outcomes.iter() {
+            match outcome {
+                Outcome::Partial => eprintln!("partial: {name}"),
+                Outcome::NotStarted => eprintln!("not started: {name}"),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{channel, message, temp_dex};
+
+    use super::*;
+
+    /// A message `n` seconds into 2020
+    fn numbered(n: u64) -> Message {
+        let timestamp = "2020-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap() + TimeDelta::seconds(n as i64);
+
+        message(*Snowflake::from_datetime(timestamp), &timestamp.to_rfc3339(), 42, &format!("message {n}"))
+    }
+
+    fn contents(parsed: &ParsedDex) -> Vec<&str> {
+        parsed.messages.iter().map(|m| m.content.as_deref().unwrap()).collect()
+    }
+
+    /// A compressed archive with a frame for each of `saves`, and where each frame starts
+    async fn compressed(name: &str, saves: &[&[u64]]) -> (PathBuf, Vec<u64>) {
+        let path = temp_dex(name, "dex.zst");
+        let mut parsed = ParsedDex::new(channel());
+        let mut starts = vec![];
+
+        for save in saves {
+            starts.push(std::fs::metadata(&path).map_or(0, |m| m.len()));
+
+            for n in *save {
+                parsed.insert(numbered(*n));
+            }
+
+            parsed.save(&path).await.unwrap();
+        }
+
+        (path, starts)
+    }
+
+    fn imported(timestamp: &str, content: &str) -> Message {
+        let mut message = message(0, timestamp, 42, content);
+
+        message.assign_synthetic_id();
+
+        message
+    }
+
+    #[test]
+    fn real_messages_replace_their_placeholders() {
+        let mut parsed = ParsedDex::new(channel());
+
+        parsed.insert(imported("2019-06-07T03:14:05Z", "My God."));
+        parsed.insert(imported("2019-06-07T03:14:05Z", "Something else"));
+        parsed.mark_saved();
+
+        let real = message(*Snowflake::from_datetime("2019-06-07T03:14:05.500Z".parse().unwrap()) + 1, "2019-06-07T03:14:05.500Z", 42, "My God.");
+        let real_id = real.id;
+
+        parsed.merge(vec![real]);
+
+        let contents: Vec<_> = parsed.messages.iter().map(|m| (m.synthetic, m.content.as_deref().unwrap())).collect();
+
+        assert_eq!(contents.len(), 2);
+        assert!(contents.contains(&(false, "My God.")));
+        assert!(contents.contains(&(true, "Something else")));
+        assert!(parsed.touched().contains(&real_id));
+        assert!(parsed.pending.iter().any(|p| matches!(p, Pending::Journal(Journal::Remove { .. }))));
+    }
+
+    #[test]
+    fn placeholders_a_second_off_stay() {
+        let mut parsed = ParsedDex::new(channel());
+
+        parsed.insert(imported("2019-06-07T03:14:04Z", "My God."));
+        parsed.mark_saved();
+
+        parsed.merge(vec![message(*Snowflake::from_datetime("2019-06-07T03:14:05Z".parse().unwrap()), "2019-06-07T03:14:05Z", 42, "My God.")]);
+
+        assert_eq!(parsed.messages.len(), 2);
+        assert!(!parsed.pending.iter().any(|p| matches!(p, Pending::Journal(Journal::Remove { .. }))));
+    }
+
+    #[tokio::test]
+    async fn a_cut_off_last_frame_is_dropped() {
+        let (path, starts) = compressed("torn", &[&[1, 2], &[3], &[4]]).await;
+        let bytes = std::fs::read(&path).unwrap();
+
+        // A crash partway through the last save
+        std::fs::write(&path, &bytes[..bytes.len() - 5]).unwrap();
+
+        let mut parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(contents(&parsed), ["message 1", "message 2", "message 3"]);
+
+        parsed.insert(numbered(5));
+        parsed.save(&path).await.unwrap();
+
+        // Appended where the cut off frame was
+        assert!(std::fs::read(&path).unwrap().starts_with(&bytes[..starts[2] as usize]));
+        assert_eq!(contents(&ParsedDex::parse(&path, false).await.unwrap()), ["message 1", "message 2", "message 3", "message 5"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_corrupt_frame_in_the_middle_is_not_a_cut_off_one() {
+        let (path, starts) = compressed("corrupt", &[&[1, 2], &[3], &[300]]).await;
+        let mut bytes = std::fs::read(&path).unwrap();
+
+        bytes[(starts[1] + starts[2]) as usize / 2] ^= 0xff;
+        std::fs::write(&path, &bytes).unwrap();
+
+        match ParsedDex::parse(&path, false).await {
+            Err(DexError::Frame { offset, .. }) => assert_eq!(offset, starts[1]),
+            other => panic!("expected a corrupt frame, got {other:?}")
+        }
+
+        // Nothing was touched
+        assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+        let mut parsed = ParsedDex::parse(&path, true).await.unwrap();
+
+        assert_eq!(contents(&parsed), ["message 1", "message 2", "message 300"]);
+
+        parsed.save(&path).await.unwrap();
+
+        let mut damaged = path.as_os_str().to_owned();
+        damaged.push(".damaged");
+
+        assert_eq!(std::fs::read(&damaged).unwrap(), bytes);
+        assert_eq!(contents(&ParsedDex::parse(&path, false).await.unwrap()), ["message 1", "message 2", "message 300"]);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_seen_again_are_no_longer_deleted() {
+        let path = temp_dex("undeleted", "dex");
+        let mut parsed = ParsedDex::new(channel());
+
+        parsed.insert(numbered(1));
+        parsed.save(&path).await.unwrap();
+
+        parsed.mark_deleted(numbered(1).id, Utc::now());
+        parsed.save(&path).await.unwrap();
+
+        assert!(ParsedDex::parse(&path, false).await.unwrap().messages[0].deleted_at.is_some());
+
+        parsed.insert(numbered(1));
+
+        assert_eq!(parsed.touched(), [numbered(1).id]);
+
+        parsed.save(&path).await.unwrap();
+
+        let parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert!(parsed.messages[0].deleted_at.is_none());
+        assert!(parsed.messages[0].revisions.is_empty());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    /// Each message as it's stored, to compare two archives by
+    fn stored(parsed: &ParsedDex) -> Vec<String> {
+        parsed.messages.iter().map(|m| serde_json::to_string(m).unwrap()).collect()
+    }
+
+    /// An archive that's been saved a few times over, with an edit, a deletion and a message backfilled before the rest
+    async fn journaled(name: &str) -> (PathBuf, ParsedDex) {
+        let path = temp_dex(name, "dex");
+        let mut parsed = ParsedDex::new(channel());
+
+        parsed.insert(numbered(2));
+        parsed.insert(numbered(3));
+        parsed.save(&path).await.unwrap();
+
+        let mut edited = numbered(2);
+        edited.content = Some(String::from("edited"));
+
+        parsed.insert(edited);
+        parsed.insert(numbered(4));
+        parsed.mark_deleted(numbered(3).id, Utc::now());
+        parsed.insert(numbered(1));
+        parsed.save(&path).await.unwrap();
+
+        (path, parsed)
+    }
+
+    #[tokio::test]
+    async fn saves_append_and_read_back_the_same() {
+        let path = temp_dex("append", "dex");
+        let mut parsed = ParsedDex::new(channel());
+
+        parsed.insert(numbered(1));
+        parsed.save(&path).await.unwrap();
+
+        let before = std::fs::read(&path).unwrap();
+
+        parsed.insert(numbered(2));
+        parsed.save(&path).await.unwrap();
+
+        let after = std::fs::read(&path).unwrap();
+
+        assert!(after.starts_with(&before));
+        assert_eq!(after[before.len()..].iter().filter(|b| **b == b'\n').count(), 1);
+
+        // Nothing to write
+        parsed.save(&path).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), after);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_journal_replays_to_what_was_saved() {
+        let (path, parsed) = journaled("replay").await;
+        let replayed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(stored(&replayed), stored(&parsed));
+        assert_eq!(contents(&replayed), ["message 1", "edited", "message 3", "message 4"]);
+        assert_eq!(replayed.messages[1].revisions.len(), 1);
+        assert!(replayed.messages[2].deleted_at.is_some());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compacting_gives_what_replaying_does() {
+        let (path, _) = journaled("compact").await;
+        let replayed = ParsedDex::parse(&path, false).await.unwrap();
+
+        let mut compacted = ParsedDex::parse(&path, false).await.unwrap();
+
+        compacted.compact(&path).await.unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+
+        assert!(!contents.contains(JOURNAL_PREFIX));
+        assert_eq!(contents.lines().count(), 5);
+        assert_eq!(stored(&ParsedDex::parse(&path, false).await.unwrap()), stored(&replayed));
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_cut_off_last_line_is_dropped_before_appending() {
+        let (path, _) = journaled("cut").await;
+        let whole = ParsedDex::parse(&path, false).await.unwrap();
+
+        // A crash partway through appending
+        let mut bytes = std::fs::read(&path).unwrap();
+        let line = serde_json::to_vec(&numbered(5)).unwrap();
+
+        bytes.extend(&line[..line.len() / 2]);
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(stored(&parsed), stored(&whole));
+        assert!(matches!(parsed.tail, Some(TailRepair::Truncate(_))));
+
+        parsed.insert(numbered(6));
+        parsed.save(&path).await.unwrap();
+
+        let parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(contents(&parsed), ["message 1", "edited", "message 3", "message 4", "message 6"]);
+        assert!(parsed.tail.is_none());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_last_line_missing_its_newline_is_kept() {
+        let (path, _) = journaled("newline").await;
+        let whole = ParsedDex::parse(&path, false).await.unwrap();
+
+        let mut bytes = std::fs::read(&path).unwrap();
+
+        bytes.pop();
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mut parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(stored(&parsed), stored(&whole));
+        assert!(matches!(parsed.tail, Some(TailRepair::Newline)));
+
+        parsed.insert(numbered(5));
+        parsed.save(&path).await.unwrap();
+
+        let parsed = ParsedDex::parse(&path, false).await.unwrap();
+
+        assert_eq!(contents(&parsed), ["message 1", "edited", "message 3", "message 4", "message 5"]);
+        assert!(parsed.tail.is_none());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
 }
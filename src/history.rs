@@ -0,0 +1,89 @@
+use walkdir::WalkDir;
+
+use crate::{args::History, config::Paths, fs::{ParsedDex, dex_id, is_dex}, sqlite::SqliteDb, store::Store, types::Message};
+
+fn print_history(message: &Message) {
+    println!("message {:#} by {}", message.id, message.author.username);
+
+    for revision in &message.revisions {
+        let when = revision.edited_timestamp.as_deref().unwrap_or("original");
+
+        println!("---- {when} ----");
+        println!("{}", revision.content.as_deref().unwrap_or(""));
+
+        for attachment in &revision.attachments {
+            println!("{}", attachment.url);
+        }
+    }
+
+    let when = match (&message.edited_timestamp, message.revisions.is_empty()) {
+        (Some(edited), _) => edited.as_str(),
+        (None, true) => "original",
+        // Edited at some point, but the API didn't tell us when
+        (None, false) => "current"
+    };
+
+    println!("---- {when} ----");
+    println!("{}", message.content.as_deref().unwrap_or(""));
+
+    for attachment in &message.attachments {
+        println!("{}", attachment.url);
+    }
+}
+
+pub async fn do_history(history: History, paths: &Paths) -> anyhow::Result<()> {
+    if paths.store == Store::Sqlite {
+        let db = SqliteDb::open_existing(&paths.sqlite())?;
+
+        for channel in db.channels()? {
+            if history.channel.is_some_and(|c| c != channel) {
+                continue;
+            }
+
+            let Some(parsed) = db.load(channel, Some(history.message..=history.message))? else {
+                continue;
+            };
+
+            if let Some(message) = parsed.messages.front() {
+                println!("in {} ({} channel {channel})", parsed.header.channel.display(), db.path().display());
+                print_history(message);
+
+                return Ok(());
+            }
+        }
+
+        return Err(anyhow::anyhow!("No message with id {} in the archive", history.message));
+    }
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if !is_dex(entry.path()) {
+            continue;
+        }
+
+        if let Some(channel) = history.channel
+            && dex_id(entry.path()) != Some(channel.to_string().as_str())
+        {
+            continue;
+        }
+
+        let parsed = match ParsedDex::parse_range(entry.path(), history.message..=history.message, history.salvage).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping: {e}");
+
+                continue;
+            }
+        };
+
+        if let Some(message) = parsed.messages.iter().find(|m| m.id == history.message) {
+            println!("in {} ({})", parsed.header.channel.display(), entry.path().display());
+            print_history(message);
+
+            return Ok(());
+        }
+    }
+
+    Err(anyhow::anyhow!("No message with id {} in the archive", history.message))
+}
@@ -3,7 +3,7 @@ use std::{collections::{HashMap}, time::Duration};
 use chrono::{NaiveDate, NaiveDateTime, NaiveTime, SubsecRound, Utc};
 use tokio::io::{AsyncBufReadExt as _, BufReader};
 
-use crate::{args::Import, fs::ParsedDex, types::{Attachment, Author, Message, U64ReprStr}};
+use crate::{args::Import, config::Paths, fs::{DbLock, pick_dex}, shutdown::Shutdown, store::{Archive, Archives}, types::{Attachment, Author, Message, Snowflake}};
 
 struct TempMessage<'a> {
     timestamp: chrono::DateTime<chrono::Utc>,
@@ -172,7 +172,7 @@ fn parse_with_headers<'a>(s: &'a str, allowed_users: Option<&[String]>) -> Optio
     Some(messages)
 }
 
-pub async fn do_import(import: Import) -> anyhow::Result<()> {
+pub async fn do_import(import: Import, paths: &Paths) -> anyhow::Result<()> {
     let s = tokio::fs::read_to_string(&import.file).await?;
 
     let mut messages = parse_with_headers(&s, None).expect("failed to parse");
@@ -233,11 +233,11 @@ pub async fn do_import(import: Import) -> anyhow::Result<()> {
             break;
         }
 
-        let Some((username, id)) = line.split_once(":") else {
+        let Some((username, Ok(id))) = line.split_once(":").map(|(u, id)| (u, id.parse::<Snowflake>())) else {
             return Err(anyhow::anyhow!("I was pretty clear on the format: username:id"));
         };
 
-        username_to_id.insert(username.to_string(), id.to_string());
+        username_to_id.insert(username.to_string(), id);
 
         eprintln!("Added user: {username} with id: {id}. Add another, or press enter to rescan and only use these guys.");
     }
@@ -264,58 +264,67 @@ pub async fn do_import(import: Import) -> anyhow::Result<()> {
         return Err(anyhow::anyhow!("No valid channel id"));
     };
 
-    let mut path = std::env::current_dir().unwrap();
+    let _lock = DbLock::acquire(&paths.db, import.wait).await?;
 
-    path.push("db");
+    let archives = Archives::open(paths, false)?;
+    let archive = match &archives {
+        Archives::Dex { db, .. } => {
+            let mut path = db.clone();
 
-    match guild_id.as_str() {
-        "" => {
-            path.push("DMs");
-        }
-        guild_id => {
-            path.push(guild_id);
-        }
+            match guild_id.as_str() {
+                "" => {
+                    path.push("DMs");
+                }
+                guild_id => {
+                    path.push(guild_id);
+                }
+            };
+
+            path.push(format!("{channel_id}.dex"));
+
+            Archive::Dex(pick_dex(path, false))
+        },
+        Archives::Sqlite(db) => Archive::Sqlite { db, channel: channel_id.trim().parse()? }
+    };
+
+    // Only what the import could be a duplicate of, which indexed archives can read without the rest
+    let window = match (messages.iter().map(|m| m.timestamp).min(), messages.iter().map(|m| m.timestamp).max()) {
+        (Some(first), Some(last)) => {
+            Snowflake::from_datetime(first - Duration::from_secs(2))..=Snowflake::from_datetime(last + Duration::from_secs(2))
+        },
+        _ => Snowflake(0)..=Snowflake(u64::MAX)
     };
 
-    path.push(format!("{channel_id}.dex"));
+    // Salvaged archives are rewritten without their bad lines on save, which takes all of it
+    let parsed = if import.salvage {
+        archive.read(true).await?
+    } else {
+        archive.read_range(window, false).await?
+    };
 
-    let Some(mut parsed) = ParsedDex::parse(&path).await else {
-        eprintln!("Couldn't find or parse the .dex file. Importing from scratch is not supported.");
+    let Some(mut parsed) = parsed else {
+        eprintln!("Couldn't find {archive}. Importing from scratch is not supported.");
 
-        return Err(anyhow::anyhow!("No .dex file found"));
+        return Err(anyhow::anyhow!("No archive found"));
     };
 
     eprintln!("Parsed! Hefty.");
 
     let mut added = 0;
+    let shutdown = Shutdown::install();
 
     for temp_message in messages {
-        let start = temp_message.timestamp - Duration::from_secs(1);
-        let end = temp_message.timestamp + Duration::from_secs(1);
-
-        let start_index = parsed.messages.binary_search_by_key(
-            &Some(start),
-            |m| m.timestamp
-        );
-        let start_index = match start_index {
-            Ok(index) => index,
-            Err(index) => index,
-        };
-        let end_index = parsed.messages.binary_search_by_key(
-            &Some(end),
-            |m| m.timestamp
-        );
-        let end_index = match end_index {
-            Ok(index) => index,
-            Err(index) => index,
-        };
+        if shutdown.requested() {
+            eprintln!("Stopped early. Saving the {added} messages imported so far, run it again for the rest.");
 
-        // .slice() on imbl::Vector takes out the elements (!!)
-        let possible_matches: Vec<_> = (start_index..=end_index).map(|index| &parsed.messages[index]).collect();
+            break;
+        }
 
-        // let possible_matches = &parsed.messages.slice(start_index..=end_index);
+        let start = temp_message.timestamp - Duration::from_secs(1);
+        let end = temp_message.timestamp + Duration::from_secs(1);
 
-        let is_doop = possible_matches.iter()
+        let is_doop = parsed.between(start, end)
+            .map(|index| &parsed.messages[index])
             .any(|m|
                 m.content.as_ref().is_some_and(|s| *s == temp_message.text) &&
                 // Match timestamps up to the second
@@ -324,26 +333,14 @@ pub async fn do_import(import: Import) -> anyhow::Result<()> {
                 )
             );
 
-        if temp_message.text == "I can never tell with you always being invis" || temp_message.text == "beck" || temp_message.text == ":c" {
-            // dbg!(possible_matches);
-            eprintln!("{is_doop} {} {} {}; {start_index}..={end_index}", temp_message.username, temp_message.text, temp_message.timestamp);
-        }
-
         if !is_doop {
-            let insert_index = parsed.messages.binary_search_by_key(
-                &Some(temp_message.timestamp),
-                |m| m.timestamp
-            );
-            let insert_index = match insert_index {
-                Ok(index) => index,
-                Err(index) => index,
-            };
+            let author_id = *username_to_id.get(temp_message.username).unwrap();
             let fake_message = Message {
-                id: U64ReprStr(0),
+                id: Snowflake::synthetic(temp_message.timestamp, author_id, &temp_message.text),
                 r#type: 0,
                 timestamp: Some(temp_message.timestamp),
                 attachments: temp_message.attachments.iter().map(|url| Attachment {
-                    id: String::from("0"),
+                    id: Snowflake(0),
                     filename: String::new(),
                     url: url.to_string(),
                     height: None,
@@ -352,28 +349,34 @@ pub async fn do_import(import: Import) -> anyhow::Result<()> {
                     original_content_type: None,
                     size: None,
                     proxy_url: None,
+                    extra: Default::default(),
                 }).collect(),
                 author: Author {
                     username: temp_message.username.to_string(),
                     avatar: None,
-                    id: username_to_id.get(temp_message.username).unwrap().clone(),
+                    id: author_id,
                     global_name: None,
+                    extra: Default::default(),
                 },
                 content: Some(temp_message.text),
                 edited_timestamp: None,
                 embeds: vec![],
                 pinned: None,
+                revisions: vec![],
+                deleted_at: None,
+                synthetic: true,
+                extra: Default::default(),
             };
 
-            parsed.messages.insert(insert_index, fake_message);
+            parsed.insert(fake_message);
 
             added += 1;
         }
     }
 
-    eprintln!("Imported {added} new messages! Saving now. It might be slow. Even on a fast ssd.");
+    eprintln!("Imported {added} new messages! Appending them now.");
 
-    parsed.save(&path).await?;
+    archive.save(&mut parsed).await?;
 
     Ok(())
 }
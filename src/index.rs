@@ -0,0 +1,374 @@
+use std::io::{Read as _, Seek as _, SeekFrom};
+use std::ops::{Range, RangeInclusive};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::fs::{JOURNAL_PREFIX, Journal};
+use crate::types::Snowflake;
+
+// How many lines go in a block, at most. Smaller blocks mean less read per lookup, and a bigger index
+const BLOCK_LINES: usize = 1000;
+
+// How much of the end of the indexed part goes into its fingerprint
+const FINGERPRINT_BYTES: u64 = 256;
+
+// The low bits of a snowflake, under its timestamp
+const SEQUENCE_BITS: u64 = (1 << 22) - 1;
+
+/// A `.dex.idx`, next to a `.dex`. Splits the file into blocks of lines, each with the range of message ids
+/// in it, so a range of ids can be read without reading the whole file. Days are a range of ids too,
+/// see `Snowflake::from_datetime`. Only for uncompressed archives, which can be seeked through
+///
+/// Messages aren't always in order in the file, and journal lines can change ones anywhere before them, so
+/// a range can be spread over any number of blocks. Lines that change the header are kept out of blocks,
+/// since every lookup has to read them
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DexIndex {
+    /// How much of the `.dex` is indexed. Anything past this was appended by something that didn't update
+    /// the index, and gets read in full
+    pub len: u64,
+    pub blocks: Vec<Block>,
+    /// Header, channel change and sync journal lines
+    pub headers: Vec<Range<u64>>,
+    /// A hash of the last bytes before `len`. Appending leaves them be, rewriting the file almost certainly doesn't
+    pub fingerprint: u64
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Block {
+    pub offset: u64,
+    pub len: u64,
+    /// The smallest and largest message id in the block, counting edits and deletions
+    pub min: Snowflake,
+    pub max: Snowflake
+}
+
+/// Just enough of a message line to place it
+#[derive(Deserialize)]
+struct MessageIds {
+    id: Snowflake,
+    timestamp: Option<DateTime<Utc>>
+}
+
+impl DexIndex {
+    pub fn path_for(dex: &Path) -> PathBuf {
+        let mut path = dex.as_os_str().to_owned();
+        path.push(".idx");
+
+        PathBuf::from(path)
+    }
+
+    /// The index for `dex`, if there's one that still matches it
+    pub fn load(dex: &Path) -> Option<Self> {
+        let contents = std::fs::read(Self::path_for(dex)).ok()?;
+        let index: DexIndex = match serde_json::from_slice(&contents) {
+            Ok(index) => index,
+            Err(e) => {
+                eprintln!("ignoring {}: {e}", Self::path_for(dex).display());
+
+                return None;
+            }
+        };
+
+        // Rewritten since, by something that didn't know about the index
+        if index.len > std::fs::metadata(dex).ok()?.len() || fingerprint(dex, index.len).ok()? != index.fingerprint {
+            return None;
+        }
+
+        Some(index)
+    }
+
+    pub async fn save(&mut self, dex: &Path) -> anyhow::Result<()> {
+        self.fingerprint = fingerprint(dex, self.len)?;
+
+        tokio::fs::write(Self::path_for(dex), serde_json::to_vec(self)?).await?;
+
+        Ok(())
+    }
+
+    /// Indexes a whole `.dex`, header line and all
+    pub fn build(contents: &[u8]) -> Self {
+        let header_len = contents.iter().position(|b| *b == b'\n').map_or(contents.len(), |i| i + 1);
+        let mut index = DexIndex { len: header_len as u64, ..Default::default() };
+
+        index.extend(&contents[header_len..]);
+
+        index
+    }
+
+    /// Indexes `appended`, the lines written after the end of what's indexed so far
+    pub fn extend(&mut self, appended: &[u8]) {
+        let mut offset = self.len;
+        let mut block: Option<Block> = None;
+        let mut lines = 0;
+
+        // Only whole lines. A cut off one is left for whoever repairs it
+        let end = appended.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+
+        for line in appended[..end].split_inclusive(|b| *b == b'\n') {
+            let range = offset..offset + line.len() as u64;
+
+            offset = range.end;
+
+            let ids = match line_ids(line) {
+                Some(ids) => ids,
+                None => {
+                    self.blocks.extend(block.take());
+
+                    lines = 0;
+
+                    self.headers.push(range);
+
+                    continue;
+                }
+            };
+
+            let current = block.get_or_insert(Block { offset: range.start, len: 0, min: *ids.start(), max: *ids.end() });
+
+            current.len += line.len() as u64;
+            current.min = current.min.min(*ids.start());
+            current.max = current.max.max(*ids.end());
+
+            lines += 1;
+
+            if lines == BLOCK_LINES {
+                self.blocks.extend(block.take());
+
+                lines = 0;
+            }
+        }
+
+        self.blocks.extend(block);
+        self.len = offset;
+    }
+
+    /// The parts of the `.dex`, `file_len` long, that have to be read to get every message in `ids` right,
+    /// in file order, not counting the header line
+    pub fn regions(&self, ids: &RangeInclusive<Snowflake>, file_len: u64) -> Vec<Range<u64>> {
+        let mut regions: Vec<Range<u64>> = self.blocks.iter()
+            .filter(|block| block.min <= *ids.end() && block.max >= *ids.start())
+            .map(|block| block.offset..block.offset + block.len)
+            .chain(self.headers.iter().cloned())
+            .chain((self.len < file_len).then_some(self.len..file_len))
+            .collect();
+
+        regions.sort_by_key(|region| region.start);
+
+        // Fewer, bigger reads
+        let mut merged: Vec<Range<u64>> = vec![];
+
+        for region in regions {
+            match merged.last_mut() {
+                Some(last) if last.end == region.start => last.end = region.end,
+                _ => merged.push(region)
+            }
+        }
+
+        merged
+    }
+}
+
+/// The ids a line could touch, or `None` for lines that change the header instead
+fn line_ids(line: &[u8]) -> Option<RangeInclusive<Snowflake>> {
+    let everything = Some(Snowflake(0)..=Snowflake(u64::MAX));
+
+    if line.starts_with(JOURNAL_PREFIX.as_bytes()) {
+        return match serde_json::from_slice::<Journal>(line) {
+            Ok(Journal::Edit { message }) => Some(message.id..=message.id),
+            Ok(Journal::Delete { id, .. } | Journal::Remove { id }) => Some(id..=id),
+            Ok(Journal::Header { .. } | Journal::Changed { .. } | Journal::Synced { .. }) => None,
+            // Always read, so whoever reads it finds out it's broken
+            Err(_) => everything
+        };
+    }
+
+    match serde_json::from_slice::<MessageIds>(line) {
+        Ok(MessageIds { id: Snowflake(0), timestamp: Some(timestamp) }) => {
+            // Imported before synthetic ids, it gets one somewhere in this second when it's parsed
+            let start = Snowflake::from_datetime(timestamp);
+
+            Some(start..=Snowflake(*start | SEQUENCE_BITS))
+        },
+        Ok(MessageIds { id, .. }) => Some(id..=id),
+        Err(_) => everything
+    }
+}
+
+/// FNV-1a of the `FINGERPRINT_BYTES` before `len` in `file`
+fn fingerprint(file: &Path, len: u64) -> std::io::Result<u64> {
+    let bytes = read_regions(file, std::iter::once(len.saturating_sub(FINGERPRINT_BYTES)..len))?;
+
+    Ok(bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3)))
+}
+
+/// Reads `regions` of `file`
+pub fn read_regions(file: &Path, regions: impl IntoIterator<Item = Range<u64>>) -> std::io::Result<Vec<u8>> {
+    let mut file = std::fs::File::open(file)?;
+    let mut contents = vec![];
+
+    for region in regions {
+        file.seek(SeekFrom::Start(region.start))?;
+        (&mut file).take(region.end - region.start).read_to_end(&mut contents)?;
+    }
+
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::{Header, ParsedDex};
+    use crate::testing::{channel, temp_dex};
+    use crate::types::Message;
+
+    use super::*;
+
+    /// Sent when its id says it was
+    fn message(id: u64, content: &str) -> Message {
+        crate::testing::message(id, &Snowflake(id).timestamp().to_rfc3339(), 42, content)
+    }
+
+    fn line(value: &impl Serialize) -> Vec<u8> {
+        let mut line = serde_json::to_vec(value).unwrap();
+
+        line.push(b'\n');
+
+        line
+    }
+
+    fn id(n: u64) -> u64 {
+        (n + 1) << 22
+    }
+
+    /// A header, 2500 messages, then a backfilled one, an edit and a deletion of early messages, and a rename
+    fn archive() -> Vec<u8> {
+        let channel = channel();
+        let mut contents = line(&Header::new(channel.clone()));
+
+        for n in 1..=2500 {
+            contents.extend(line(&message(id(n), &format!("message {n}"))));
+        }
+
+        contents.extend(line(&message(id(0), "backfilled")));
+        contents.extend(line(&Journal::Edit { message: message(id(10), "edited") }));
+        contents.extend(line(&Journal::Delete { id: Snowflake(id(20)), deleted_at: Utc::now() }));
+        contents.extend(line(&Journal::Header { channel }));
+
+        contents
+    }
+
+    fn contents(parsed: &ParsedDex) -> Vec<String> {
+        parsed.messages.iter().map(|m| serde_json::to_string(m).unwrap()).collect()
+    }
+
+    fn within(parsed: &ParsedDex, ids: &RangeInclusive<Snowflake>) -> Vec<String> {
+        parsed.messages.iter().filter(|m| ids.contains(&m.id)).map(|m| serde_json::to_string(m).unwrap()).collect()
+    }
+
+    #[test]
+    fn blocks_cover_every_message_line() {
+        let archive = archive();
+        let index = DexIndex::build(&archive);
+
+        assert_eq!(index.len, archive.len() as u64);
+        assert_eq!(index.blocks.len(), 3);
+        assert_eq!(index.headers.len(), 1);
+        assert!(archive[index.headers[0].start as usize..].starts_with(b"{\"journal\":\"header\""));
+
+        // The last block has the backfill, the edit and the deletion in it
+        let last = index.blocks.last().unwrap();
+
+        assert_eq!(last.min, Snowflake(id(0)));
+        assert_eq!(last.max, Snowflake(id(2500)));
+
+        let covered: u64 = index.blocks.iter().map(|b| b.len).sum::<u64>() + index.headers.iter().map(|h| h.end - h.start).sum::<u64>();
+        let header_line = archive.iter().position(|b| *b == b'\n').unwrap() as u64 + 1;
+
+        assert_eq!(covered + header_line, archive.len() as u64);
+    }
+
+    /// The lines in `regions` of `archive` that touch a message in `ids`
+    fn touching<'a>(archive: &'a [u8], regions: impl IntoIterator<Item = Range<u64>>, ids: &RangeInclusive<Snowflake>) -> Vec<&'a [u8]> {
+        regions.into_iter()
+            .flat_map(|region| archive[region.start as usize..region.end as usize].split_inclusive(|b| *b == b'\n'))
+            .filter(|line| line_ids(line).is_some_and(|touched| touched.start() <= ids.end() && touched.end() >= ids.start()))
+            .collect()
+    }
+
+    #[test]
+    fn extending_finds_what_building_does() {
+        let archive = archive();
+        let split = archive.len() - 300;
+        let split = archive[..split].iter().rposition(|b| *b == b'\n').unwrap() + 1;
+
+        let mut extended = DexIndex::build(&archive[..split]);
+
+        extended.extend(&archive[split..]);
+
+        let built = DexIndex::build(&archive);
+        let header_line = archive.iter().position(|b| *b == b'\n').unwrap() as u64 + 1;
+
+        assert_eq!(extended.len, built.len);
+        assert_eq!(extended.headers, built.headers);
+
+        for (start, end) in [(0, 0), (10, 10), (20, 20), (500, 1500), (2490, 2500)] {
+            let ids = Snowflake(id(start))..=Snowflake(id(end));
+            let expected = touching(&archive, std::iter::once(header_line..archive.len() as u64), &ids);
+
+            assert_eq!(touching(&archive, extended.regions(&ids, archive.len() as u64), &ids), expected, "{start}..={end}");
+            assert_eq!(touching(&archive, built.regions(&ids, archive.len() as u64), &ids), expected, "{start}..={end}");
+        }
+    }
+
+    #[tokio::test]
+    async fn ranges_read_the_same_as_a_full_parse() {
+        let path = temp_dex("ranges", "dex");
+
+        std::fs::write(&path, archive()).unwrap();
+        let mut index = DexIndex::build(&std::fs::read(&path).unwrap());
+
+        index.save(&path).await.unwrap();
+
+        let full = ParsedDex::parse(&path, false).await.unwrap();
+
+        for ids in [(0, 0), (10, 10), (20, 20), (500, 1500), (1999, 2001), (2400, 3000)].map(|(start, end)| Snowflake(id(start))..=Snowflake(id(end))).into_iter().chain([Snowflake(0)..=Snowflake(u64::MAX)]) {
+            let ranged = ParsedDex::parse_range(&path, ids.clone(), false).await.unwrap();
+
+            assert_eq!(contents(&ranged), within(&full, &ids), "{ids:?}");
+            assert_eq!(ranged.header.channel.id(), full.header.channel.id());
+        }
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bad_lines_fall_back_to_a_full_parse() {
+        let mut archive = archive();
+        let index = DexIndex::build(&archive);
+
+        // Same length, so the index still matches the file
+        let block = &index.blocks[1];
+        let start = block.offset as usize;
+
+        archive[start] = b'#';
+
+        let path = temp_dex("fallback", "dex");
+
+        std::fs::write(&path, &archive).unwrap();
+        let mut index = index;
+
+        index.save(&path).await.unwrap();
+
+        let ids = Snowflake(id(1500))..=Snowflake(id(1600));
+
+        assert!(ParsedDex::parse_range(&path, ids.clone(), false).await.is_err());
+
+        let ranged = ParsedDex::parse_range(&path, ids.clone(), true).await.unwrap();
+
+        assert_eq!(contents(&ranged), within(&ParsedDex::parse(&path, true).await.unwrap(), &ids));
+        assert_eq!(ranged.messages.len(), 101);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
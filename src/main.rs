@@ -3,49 +3,141 @@
 mod args;
 mod types;
 mod api;
+mod ratelimit;
 mod fs;
 mod update;
 mod import;
 mod export;
 mod download;
+mod history;
+mod compact;
+mod shutdown;
+mod config;
+mod verify;
+mod migrate;
+mod compress;
+mod index;
+mod reindex;
+mod sqlite;
+mod store;
+mod convert;
+#[cfg(test)]
+mod testing;
 
 use clap::Parser;
 
-use args::DexporterOpts;
+use args::{Command, DexporterOpts};
+use config::Paths;
 
 #[tokio::main]
 async fn main() {
     let options = DexporterOpts::parse();
 
-    match options {
-        DexporterOpts::Import(import) => {
-            let result = import::do_import(import).await;
+    let paths = match Paths::resolve(&options) {
+        Ok(paths) => paths,
+        Err(e) => {
+            eprintln!("A mistake: {e}");
+            eprintln!("Fix it.");
+
+            return;
+        }
+    };
+
+    match options.command {
+        Command::Import(import) => {
+            let result = import::do_import(import, &paths).await;
 
             if let Err(e) = result {
                 eprintln!("A mistake: {e}");
                 eprintln!("Fix it.");
             }
         },
-        DexporterOpts::Export(export) => {
-            let result = export::do_export(export).await;
+        Command::Export(export) => {
+            let result = export::do_export(export, &paths).await;
 
             if let Err(e) = result {
                 eprintln!("A mistake: {e}");
                 eprintln!("Fix it.");
             }
         },
-        DexporterOpts::Update(update) => {
-            match update::do_update(update).await {
+        Command::Update(update) => {
+            match update::do_update(update, &paths).await {
                 Ok(()) => {
                     eprintln!("Finished ok?");
                 },
-                Err(_) => {
-                    eprintln!("Something went wrong.");
+                Err(e) => {
+                    eprintln!("Something went wrong: {e}");
                 }
             }
         },
-        DexporterOpts::Download(download) => {
-            let result = download::do_download(download).await;
+        Command::Download(download) => {
+            let result = download::do_download(download, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::History(history) => {
+            let result = history::do_history(history, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Compact(compact) => {
+            let result = compact::do_compact(compact, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Verify(verify) => {
+            let result = verify::do_verify(verify, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+
+                std::process::exit(1);
+            }
+        },
+        Command::Migrate(migrate) => {
+            let result = migrate::do_migrate(migrate, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Compress(compress) => {
+            let result = compress::do_compress(compress, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Decompress(decompress) => {
+            let result = compress::do_decompress(decompress, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Index(index) => {
+            let result = reindex::do_index(index, &paths).await;
+
+            if let Err(e) = result {
+                eprintln!("A mistake: {e}");
+                eprintln!("Fix it.");
+            }
+        },
+        Command::Convert(convert) => {
+            let result = convert::do_convert(convert, &paths).await;
 
             if let Err(e) = result {
                 eprintln!("A mistake: {e}");
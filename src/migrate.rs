@@ -0,0 +1,48 @@
+use walkdir::WalkDir;
+
+use crate::{args::Migrate, config::Paths, fs::{DbLock, FORMAT_VERSION, ParsedDex, is_dex}};
+
+pub async fn do_migrate(migrate: Migrate, paths: &Paths) -> anyhow::Result<()> {
+    paths.require_dex("migrate")?;
+
+    let _lock = DbLock::acquire(&paths.db, migrate.wait).await?;
+
+    let mut migrated = 0;
+    let mut failed = 0;
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if !is_dex(entry.path()) {
+            continue;
+        }
+
+        let mut parsed = match ParsedDex::parse(entry.path(), migrate.salvage).await {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                eprintln!("skipping: {e}");
+
+                failed += 1;
+
+                continue;
+            }
+        };
+
+        let version = parsed.header.dex;
+
+        if version == FORMAT_VERSION {
+            continue;
+        }
+
+        // Writing it out in full is all an upgrade takes, the header is always written as the current version
+        parsed.compact(entry.path()).await?;
+
+        eprintln!("migrated: {} version {version} -> {FORMAT_VERSION}", entry.path().display());
+
+        migrated += 1;
+    }
+
+    eprintln!("done: {migrated} migrated, {failed} couldn't be read");
+
+    Ok(())
+}
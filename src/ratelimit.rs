@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Body Discord sends along with a 429
+#[derive(Debug, Deserialize)]
+pub struct RateLimitedBody {
+    pub retry_after: f64,
+    #[serde(default)]
+    pub global: bool
+}
+
+#[derive(Debug)]
+struct Bucket {
+    remaining: u32,
+    reset: Instant
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    // Route path -> bucket key. We only learn a route's bucket after its first response
+    routes: HashMap<String, String>,
+    buckets: HashMap<String, Bucket>,
+    global_reset: Option<Instant>,
+    window_start: Instant,
+    window_count: u32
+}
+
+/// Keeps track of Discord's per-bucket and global limits for every request made through a client,
+/// and holds requests back until they can go through without being 429'd
+#[derive(Debug)]
+pub struct RateLimiter {
+    state: Mutex<LimiterState>,
+    global_per_second: u32
+}
+
+impl RateLimiter {
+    pub fn new(global_per_second: u32) -> Self {
+        RateLimiter {
+            state: Mutex::new(LimiterState {
+                routes: HashMap::new(),
+                buckets: HashMap::new(),
+                global_reset: None,
+                window_start: Instant::now(),
+                window_count: 0
+            }),
+            global_per_second: global_per_second.max(1)
+        }
+    }
+
+    /// Waits until a request to `route` is allowed, and reserves a slot for it
+    pub async fn acquire(&self, route: &str) {
+        loop {
+            let wait = self.try_acquire(route, Instant::now());
+
+            match wait {
+                Some(until) => tokio::time::sleep_until(until).await,
+                None => return
+            }
+        }
+    }
+
+    fn try_acquire(&self, route: &str, now: Instant) -> Option<Instant> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(reset) = state.global_reset {
+            if reset > now {
+                return Some(reset);
+            }
+
+            state.global_reset = None;
+        }
+
+        if now.duration_since(state.window_start) >= Duration::from_secs(1) {
+            state.window_start = now;
+            state.window_count = 0;
+        }
+
+        if state.window_count >= self.global_per_second {
+            return Some(state.window_start + Duration::from_secs(1));
+        }
+
+        let key = state.routes.get(route).cloned();
+
+        if let Some(bucket) = key.and_then(|key| state.buckets.get_mut(&key))
+            && bucket.reset > now
+        {
+            if bucket.remaining == 0 {
+                return Some(bucket.reset);
+            }
+
+            bucket.remaining -= 1;
+        }
+
+        state.window_count += 1;
+
+        None
+    }
+
+    /// Records the X-RateLimit-* headers of a response to `route`
+    pub fn update(&self, route: &str, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+        let Some(bucket) = header("x-ratelimit-bucket") else {
+            return;
+        };
+
+        let remaining = header("x-ratelimit-remaining").and_then(|v| v.parse::<u32>().ok());
+        let reset_after = header("x-ratelimit-reset-after").and_then(|v| v.parse::<f64>().ok());
+
+        let key = format!("{bucket}:{}", major_parameter(route));
+        let mut state = self.state.lock().unwrap();
+
+        state.routes.insert(route.to_string(), key.clone());
+
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            let reset = Instant::now() + Duration::from_secs_f64(reset_after.max(0.0));
+
+            match state.buckets.get_mut(&key) {
+                // Responses can come back out of order. Within the same window, trust the lowest count
+                Some(existing) if existing.reset > Instant::now() && existing.remaining < remaining => {
+                    existing.reset = existing.reset.max(reset);
+                },
+                _ => {
+                    state.buckets.insert(key, Bucket { remaining, reset });
+                }
+            }
+        }
+    }
+
+    /// Records a 429. Everything waiting on the same bucket, or everything at all if it's global, backs off
+    pub fn limited(&self, route: &str, retry_after: Duration, global: bool) {
+        let reset = Instant::now() + retry_after;
+        let mut state = self.state.lock().unwrap();
+
+        if global {
+            state.global_reset = Some(state.global_reset.map_or(reset, |r| r.max(reset)));
+
+            return;
+        }
+
+        let key = state.routes.get(route).cloned()
+            .unwrap_or_else(|| route.to_string());
+
+        state.routes.insert(route.to_string(), key.clone());
+        state.buckets.insert(key, Bucket { remaining: 0, reset });
+    }
+}
+
+/// Discord scopes buckets per channel, guild or webhook: the first id in the route
+fn major_parameter(route: &str) -> &str {
+    let mut segments = route.split('/');
+
+    while let Some(segment) = segments.next() {
+        if matches!(segment, "channels" | "guilds" | "webhooks") {
+            return segments.next().unwrap_or("");
+        }
+    }
+
+    ""
+}
+
+#[cfg(test)]
+mod tests {
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    const ROUTE: &str = "/api/v9/channels/1/messages";
+
+    fn headers(remaining: u32, reset_after: f64) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        headers.insert("x-ratelimit-bucket", HeaderValue::from_static("abc"));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from_str(&remaining.to_string()).unwrap());
+        headers.insert("x-ratelimit-reset-after", HeaderValue::from_str(&reset_after.to_string()).unwrap());
+
+        headers
+    }
+
+    #[test]
+    fn waits_for_an_empty_bucket_to_reset() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+
+        limiter.update(ROUTE, &headers(1, 60.0));
+
+        assert_eq!(limiter.try_acquire(ROUTE, now), None);
+
+        let reset = limiter.try_acquire(ROUTE, now).expect("bucket should be empty");
+
+        assert!(reset > now + Duration::from_secs(59));
+        assert_eq!(limiter.try_acquire(ROUTE, reset + Duration::from_millis(1)), None);
+    }
+
+    #[test]
+    fn keeps_the_lowest_count_from_out_of_order_responses() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+
+        limiter.update(ROUTE, &headers(2, 60.0));
+        // Sent earlier, answered later
+        limiter.update(ROUTE, &headers(5, 60.0));
+
+        assert_eq!(limiter.try_acquire(ROUTE, now), None);
+        assert_eq!(limiter.try_acquire(ROUTE, now), None);
+        assert!(limiter.try_acquire(ROUTE, now).is_some());
+    }
+
+    #[test]
+    fn buckets_are_per_major_parameter() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+
+        limiter.update(ROUTE, &headers(0, 60.0));
+
+        assert!(limiter.try_acquire(ROUTE, now).is_some());
+        assert_eq!(limiter.try_acquire("/api/v9/channels/2/messages", now), None);
+    }
+
+    #[test]
+    fn holds_to_the_global_rate() {
+        let limiter = RateLimiter::new(2);
+        let now = Instant::now();
+
+        assert_eq!(limiter.try_acquire(ROUTE, now), None);
+        assert_eq!(limiter.try_acquire("/api/v9/users/@me", now), None);
+
+        let next_window = limiter.try_acquire(ROUTE, now).expect("window should be full");
+
+        assert_eq!(limiter.try_acquire(ROUTE, next_window), None);
+    }
+
+    #[test]
+    fn global_429_holds_back_every_route() {
+        let limiter = RateLimiter::new(1000);
+        let now = Instant::now();
+
+        limiter.limited(ROUTE, Duration::from_secs(30), true);
+
+        assert!(limiter.try_acquire("/api/v9/users/@me", now).is_some());
+        assert_eq!(limiter.try_acquire("/api/v9/users/@me", now + Duration::from_secs(31)), None);
+    }
+
+    #[test]
+    fn major_parameter_is_the_first_id() {
+        assert_eq!(major_parameter("/api/v9/channels/123/messages"), "123");
+        assert_eq!(major_parameter("/api/v9/guilds/456/threads/active"), "456");
+        assert_eq!(major_parameter("/api/v9/users/@me"), "");
+    }
+}
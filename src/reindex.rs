@@ -0,0 +1,43 @@
+use walkdir::WalkDir;
+
+use crate::{args::Index, config::Paths, fs::{DbLock, is_compressed, is_dex}, index::DexIndex};
+
+pub async fn do_index(index: Index, paths: &Paths) -> anyhow::Result<()> {
+    paths.require_dex("index")?;
+
+    let _lock = DbLock::acquire(&paths.db, index.wait).await?;
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if !is_dex(entry.path()) {
+            continue;
+        }
+
+        let index_path = DexIndex::path_for(entry.path());
+
+        if index.remove {
+            if index_path.exists() {
+                tokio::fs::remove_file(&index_path).await?;
+
+                eprintln!("removed: {}", index_path.display());
+            }
+
+            continue;
+        }
+
+        if is_compressed(entry.path()) {
+            eprintln!("skipping: {}; compressed archives can't be indexed", entry.path().display());
+
+            continue;
+        }
+
+        let mut built = DexIndex::build(&tokio::fs::read(entry.path()).await?);
+
+        built.save(entry.path()).await?;
+
+        eprintln!("indexed: {} {} blocks", entry.path().display(), built.blocks.len());
+    }
+
+    Ok(())
+}
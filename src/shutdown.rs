@@ -0,0 +1,58 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set once the user asks us to stop. Long running loops check it between requests so they get a chance
+/// to save what they have. A second Ctrl-C exits on the spot
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>
+}
+
+impl Shutdown {
+    /// Starts listening for SIGINT, and SIGTERM where there's such a thing
+    pub fn install() -> Self {
+        let shutdown = Shutdown::default();
+        let requested = shutdown.requested.clone();
+
+        tokio::spawn(async move {
+            loop {
+                wait_for_signal().await;
+
+                if requested.swap(true, Ordering::SeqCst) {
+                    eprintln!("stopping right now, anything not yet saved is lost");
+
+                    std::process::exit(130);
+                }
+
+                eprintln!("stopping: letting in-flight requests finish and saving. Ctrl-C again to quit immediately");
+            }
+        });
+
+        shutdown
+    }
+
+    pub fn requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+        let _ = tokio::signal::ctrl_c().await;
+
+        return;
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,334 @@
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use rusqlite::{Connection, OpenFlags, OptionalExtension as _, Transaction, params};
+
+use crate::fs::{Header, ParsedDex};
+use crate::types::{Channel, Message, Snowflake};
+
+/// The database `--store sqlite` keeps everything in, inside the db directory
+pub const SQLITE_FILE: &str = "archive.sqlite";
+
+/// The layout this build writes, kept in `PRAGMA user_version`
+const SCHEMA_VERSION: u32 = 1;
+
+// Messages are stored whole in `data`, so they read back exactly as they'd come out of a .dex. The other
+// columns and tables are copies of parts of it, there to be queried
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS channels (
+        id INTEGER PRIMARY KEY,
+        guild_id INTEGER,
+        parent_id INTEGER,
+        name TEXT,
+        -- The header a .dex starts with, sync state and past names included
+        header TEXT NOT NULL
+    );
+
+    -- As of the last message saved from them
+    CREATE TABLE IF NOT EXISTS users (
+        id INTEGER PRIMARY KEY,
+        username TEXT NOT NULL,
+        global_name TEXT,
+        avatar TEXT
+    );
+
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        channel_id INTEGER NOT NULL REFERENCES channels (id),
+        author_id INTEGER NOT NULL REFERENCES users (id),
+        timestamp TEXT,
+        edited_timestamp TEXT,
+        deleted_at TEXT,
+        content TEXT,
+        synthetic INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+
+    CREATE INDEX IF NOT EXISTS messages_by_channel ON messages (channel_id, id);
+
+    CREATE TABLE IF NOT EXISTS attachments (
+        message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        filename TEXT NOT NULL,
+        url TEXT NOT NULL,
+        content_type TEXT,
+        size INTEGER,
+        PRIMARY KEY (message_id, position)
+    );
+
+    CREATE TABLE IF NOT EXISTS embeds (
+        message_id INTEGER NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        type TEXT,
+        title TEXT,
+        description TEXT,
+        url TEXT,
+        PRIMARY KEY (message_id, position)
+    );
+
+    -- SELECT id FROM messages WHERE id IN (SELECT rowid FROM messages_fts WHERE messages_fts MATCH '...')
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (content, content = 'messages', content_rowid = 'id');
+
+    CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+        INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+    END;
+
+    CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+        INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+        INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+    END;
+";
+
+/// Every channel's archive in one SQLite database. Shared between the channels being updated at once,
+/// each save is a transaction of its own
+#[derive(Debug)]
+pub struct SqliteDb {
+    path: PathBuf,
+    conn: Mutex<Connection>
+}
+
+impl SqliteDb {
+    /// Opens the database, creating it if it isn't there yet
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Self::init(Connection::open(path)?, path)
+    }
+
+    /// Opens the database only if it's there, for commands that read from it
+    pub fn open_existing(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Err(anyhow::anyhow!("there's no {}, is --db right?", path.display()));
+        }
+
+        let flags = OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_URI | OpenFlags::SQLITE_OPEN_NO_MUTEX;
+
+        Self::init(Connection::open_with_flags(path, flags)?, path)
+    }
+
+    fn init(mut conn: Connection, path: &Path) -> anyhow::Result<Self> {
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version > SCHEMA_VERSION {
+            return Err(anyhow::anyhow!("{} was written by a newer dexporter (schema {version}), update this one", path.display()));
+        }
+
+        // Only writes when there's something to set up, so readers don't wait on the write lock
+        if version < SCHEMA_VERSION {
+            // Lets export read while an update is writing. Sticks to the file once set
+            conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+
+            let tx = conn.transaction()?;
+
+            tx.execute_batch(SCHEMA)?;
+            tx.execute_batch(&format!("PRAGMA user_version = {SCHEMA_VERSION};"))?;
+            tx.commit()?;
+        }
+
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+        Ok(SqliteDb { path: path.to_path_buf(), conn: Mutex::new(conn) })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Every archived channel, by id
+    pub fn channels(&self) -> anyhow::Result<Vec<Snowflake>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT id FROM channels ORDER BY id")?;
+        let ids = statement.query_map([], |row| row.get::<_, i64>(0))?
+            .map(|id| id.map(|id| Snowflake(id as u64)))
+            .collect::<Result<_, _>>()?;
+
+        Ok(ids)
+    }
+
+    /// A channel's archive, or `None` if it isn't in here. With `ids`, only the messages in that range
+    pub fn load(&self, channel: Snowflake, ids: Option<RangeInclusive<Snowflake>>) -> anyhow::Result<Option<ParsedDex>> {
+        let conn = self.conn.lock().unwrap();
+
+        let header: Option<String> = conn
+            .query_row("SELECT header FROM channels WHERE id = ?1", [sql_id(channel)], |row| row.get(0))
+            .optional()?;
+
+        let Some(header) = header else {
+            return Ok(None);
+        };
+
+        let header: Header = serde_json::from_str(&header)?;
+        let (start, end) = ids.as_ref().map_or((Snowflake(0), Snowflake(u64::MAX)), |ids| (*ids.start(), *ids.end()));
+
+        let mut statement = conn.prepare("SELECT data FROM messages WHERE channel_id = ?1 AND id BETWEEN ?2 AND ?3 ORDER BY id")?;
+        let mut rows = statement.query(params![sql_id(channel), sql_id(start), sql_id(end)])?;
+        let mut messages = imbl::vector![];
+
+        while let Some(row) = rows.next()? {
+            let data: String = row.get(0)?;
+
+            messages.push_back(serde_json::from_str::<Message>(&data)?);
+        }
+
+        Ok(Some(ParsedDex::from_parts(header, messages, ids.is_some())))
+    }
+
+    /// Writes the header, and the messages that changed since the archive was loaded or last saved
+    pub fn save(&self, parsed: &mut ParsedDex) -> anyhow::Result<()> {
+        let mut touched = parsed.touched();
+
+        touched.sort();
+        touched.dedup();
+
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let channel = Snowflake(parsed.header.channel.id());
+
+        save_channel(&tx, &parsed.header)?;
+
+        for id in touched {
+            match parsed.messages.binary_search_by_key(&id, |m| m.id) {
+                Ok(index) => save_message(&tx, channel, &parsed.messages[index])?,
+                // Dropped placeholders
+                Err(_) => {
+                    tx.execute("DELETE FROM messages WHERE id = ?1", [sql_id(id)])?;
+                }
+            }
+        }
+
+        tx.commit()?;
+
+        parsed.mark_saved();
+
+        Ok(())
+    }
+
+    /// Writes a whole archive, replacing whatever this channel had in here before
+    pub fn replace(&self, parsed: &mut ParsedDex) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let channel = Snowflake(parsed.header.channel.id());
+
+        tx.execute("DELETE FROM messages WHERE channel_id = ?1", [sql_id(channel)])?;
+
+        save_channel(&tx, &parsed.header)?;
+
+        for message in parsed.messages.iter() {
+            save_message(&tx, channel, message)?;
+        }
+
+        tx.commit()?;
+
+        parsed.mark_saved();
+
+        Ok(())
+    }
+
+    /// Drops a channel and its messages. Users stay, other channels may have their messages
+    pub fn remove(&self, channel: Snowflake) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        tx.execute("DELETE FROM messages WHERE channel_id = ?1", [sql_id(channel)])?;
+        tx.execute("DELETE FROM channels WHERE id = ?1", [sql_id(channel)])?;
+
+        tx.commit()?;
+
+        Ok(())
+    }
+}
+
+// SQLite integers are signed. Snowflakes don't reach the top bit until the 2150s, clamp in case
+fn sql_id(id: Snowflake) -> i64 {
+    id.0.min(i64::MAX as u64) as i64
+}
+
+fn save_channel(tx: &Transaction, header: &Header) -> anyhow::Result<()> {
+    let channel = &header.channel;
+    let guild_id = match channel {
+        Channel::TextChannel(c) => Some(sql_id(c.guild_id)),
+        Channel::DMChannel(_) => None
+    };
+
+    tx.execute(
+        "INSERT INTO channels (id, guild_id, parent_id, name, header) VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (id) DO UPDATE SET
+                guild_id = excluded.guild_id, parent_id = excluded.parent_id, name = excluded.name, header = excluded.header",
+        params![
+            sql_id(Snowflake(channel.id())),
+            guild_id,
+            channel.parent_id().map(sql_id),
+            channel.display(),
+            serde_json::to_string(header)?
+        ]
+    )?;
+
+    Ok(())
+}
+
+fn save_message(tx: &Transaction, channel: Snowflake, message: &Message) -> anyhow::Result<()> {
+    let author = &message.author;
+
+    tx.execute(
+        "INSERT INTO users (id, username, global_name, avatar) VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (id) DO UPDATE SET
+                username = excluded.username, global_name = excluded.global_name, avatar = excluded.avatar",
+        params![sql_id(author.id), author.username, author.global_name, author.avatar]
+    )?;
+
+    tx.execute(
+        "INSERT INTO messages (id, channel_id, author_id, timestamp, edited_timestamp, deleted_at, content, synthetic, data)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            ON CONFLICT (id) DO UPDATE SET
+                channel_id = excluded.channel_id, author_id = excluded.author_id, timestamp = excluded.timestamp,
+                edited_timestamp = excluded.edited_timestamp, deleted_at = excluded.deleted_at, content = excluded.content,
+                synthetic = excluded.synthetic, data = excluded.data",
+        params![
+            sql_id(message.id),
+            sql_id(channel),
+            sql_id(author.id),
+            message.timestamp.map(|t| t.to_rfc3339()),
+            message.edited_timestamp,
+            message.deleted_at.map(|t| t.to_rfc3339()),
+            message.content,
+            message.synthetic,
+            serde_json::to_string(message)?
+        ]
+    )?;
+
+    // Whatever was there for the old version goes
+    tx.execute("DELETE FROM attachments WHERE message_id = ?1", [sql_id(message.id)])?;
+    tx.execute("DELETE FROM embeds WHERE message_id = ?1", [sql_id(message.id)])?;
+
+    for (position, attachment) in message.attachments.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO attachments (message_id, position, id, filename, url, content_type, size) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                sql_id(message.id),
+                position,
+                sql_id(attachment.id),
+                attachment.filename,
+                attachment.url,
+                attachment.content_type,
+                attachment.size
+            ]
+        )?;
+    }
+
+    for (position, embed) in message.embeds.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO embeds (message_id, position, type, title, description, url) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![sql_id(message.id), position, embed.r#type, embed.title, embed.description, embed.url]
+        )?;
+    }
+
+    Ok(())
+}
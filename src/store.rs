@@ -0,0 +1,94 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::config::Paths;
+use crate::fs::{ParsedDex, dex_path, pick_dex};
+use crate::sqlite::SqliteDb;
+use crate::types::{Channel, Snowflake};
+
+/// What the archives are kept in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Store {
+    /// A .dex file per channel, under the db directory
+    #[default]
+    Dex,
+    /// A single SQLite database in the db directory, with full-text search over message content
+    Sqlite
+}
+
+/// An opened store, to get channels' archives out of
+pub enum Archives {
+    Dex { db: PathBuf, compress: bool },
+    Sqlite(SqliteDb)
+}
+
+impl Archives {
+    /// `compress` starts new `.dex` archives compressed, see `update --compress`
+    pub fn open(paths: &Paths, compress: bool) -> anyhow::Result<Self> {
+        Ok(match paths.store {
+            Store::Dex => Archives::Dex { db: paths.db.clone(), compress },
+            Store::Sqlite => Archives::Sqlite(SqliteDb::open(&paths.sqlite())?)
+        })
+    }
+
+    pub fn archive(&self, channel: &Channel) -> Archive<'_> {
+        match self {
+            Archives::Dex { db, compress } => Archive::Dex(pick_dex(dex_path(db, channel), *compress)),
+            Archives::Sqlite(db) => Archive::Sqlite { db, channel: Snowflake(channel.id()) }
+        }
+    }
+}
+
+/// Where a single channel's archive is read from and saved to
+pub enum Archive<'a> {
+    Dex(PathBuf),
+    Sqlite { db: &'a SqliteDb, channel: Snowflake }
+}
+
+impl Archive<'_> {
+    /// The whole archive, or `None` if the channel hasn't been archived yet
+    pub async fn read(&self, salvage: bool) -> anyhow::Result<Option<ParsedDex>> {
+        match self {
+            Archive::Dex(path) => match ParsedDex::parse(path, salvage).await {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(e) if e.is_missing() => Ok(None),
+                Err(e) => Err(e.into())
+            },
+            Archive::Sqlite { db, channel } => db.load(*channel, None)
+        }
+    }
+
+    /// Enough of the archive to get the messages in `ids` right, see `ParsedDex::parse_range`
+    pub async fn read_range(&self, ids: RangeInclusive<Snowflake>, salvage: bool) -> anyhow::Result<Option<ParsedDex>> {
+        match self {
+            Archive::Dex(path) => match ParsedDex::parse_range(path, ids, salvage).await {
+                Ok(parsed) => Ok(Some(parsed)),
+                Err(e) if e.is_missing() => Ok(None),
+                Err(e) => Err(e.into())
+            },
+            Archive::Sqlite { db, channel } => db.load(*channel, Some(ids))
+        }
+    }
+
+    /// Writes out whatever changed since it was read or last saved
+    pub async fn save(&self, parsed: &mut ParsedDex) -> anyhow::Result<()> {
+        match self {
+            Archive::Dex(path) => parsed.save(path).await,
+            Archive::Sqlite { db, .. } => db.save(parsed)
+        }
+    }
+}
+
+impl fmt::Display for Archive<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Archive::Dex(path) => write!(f, "{}", path.display()),
+            Archive::Sqlite { db, channel } => write!(f, "{} channel {channel}", db.path().display())
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! What the tests build their messages and archives out of
+
+use std::path::PathBuf;
+
+use crate::types::{Channel, Message};
+
+/// A plain message, with only what Discord always sends
+pub fn message(id: u64, timestamp: &str, author: u64, content: &str) -> Message {
+    serde_json::from_value(serde_json::json!({
+        "id": id.to_string(),
+        "type": 0,
+        "timestamp": timestamp,
+        "author": { "id": author.to_string(), "username": "someone" },
+        "content": content,
+        "attachments": [],
+        "embeds": []
+    })).unwrap()
+}
+
+/// The DM the test archives are of
+pub fn channel() -> Channel {
+    serde_json::from_value(serde_json::json!({ "type": 1, "id": "7", "recipients": [] })).unwrap()
+}
+
+/// Where an archive of `channel()` goes, ending in `extension`, in a directory of its own that starts out empty
+pub fn temp_dex(name: &str, extension: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("dexporter-test-{}-{name}", std::process::id()));
+
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    dir.join(format!("7.{extension}"))
+}
@@ -1,10 +1,74 @@
 use serde::{Deserialize, Serialize, de::Visitor};
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SubsecRound, Utc};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct U64ReprStr(pub u64);
+// First millisecond of 2015, which is where snowflake timestamps count from
+pub const DISCORD_EPOCH: i64 = 1420070400000;
 
-impl std::ops::Deref for U64ReprStr {
+/// A Discord id. These are string-encoded u64s on the wire, with their creation time in the top 42 bits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Snowflake(pub u64);
+
+impl Snowflake {
+    /// The smallest snowflake that could have been created at `datetime`
+    pub fn from_datetime(datetime: DateTime<Utc>) -> Self {
+        let millis = (datetime.timestamp_millis() - DISCORD_EPOCH).max(0) as u64;
+
+        Snowflake(millis << 22)
+    }
+
+    /// A made up but stable id for a message we only know from an import. It sorts at the message's
+    /// timestamp, with the low bits, which a real id uses for worker and sequence, hashed from its contents
+    pub fn synthetic(timestamp: DateTime<Utc>, author: Snowflake, content: &str) -> Self {
+        // FNV-1a. std's hasher isn't guaranteed to hash the same way across releases
+        let mut hash: u64 = 0xcbf29ce484222325;
+
+        for byte in author.0.to_le_bytes().iter().chain(content.as_bytes()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+
+        Snowflake(Snowflake::from_datetime(timestamp).0 | (hash & 0x3FFFFF))
+    }
+
+    /// When this id was created
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        let millis = (self.0 >> 22) as i64 + DISCORD_EPOCH;
+
+        DateTime::from_timestamp_millis(millis).unwrap_or_default()
+    }
+
+    /// Parses either a raw snowflake or a date (2024-01-31, 2024-01-31T12:00:00 or RFC 3339)
+    pub fn from_id_or_date(s: &str) -> Result<Self, String> {
+        if let Ok(id) = s.parse::<u64>() {
+            return Ok(Snowflake(id));
+        }
+
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+            return Ok(Snowflake::from_datetime(datetime.with_timezone(&Utc)));
+        }
+
+        if let Ok(datetime) = chrono::NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S") {
+            return Ok(Snowflake::from_datetime(datetime.and_utc()));
+        }
+
+        if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            return Ok(Snowflake::from_datetime(date.and_time(chrono::NaiveTime::MIN).and_utc()));
+        }
+
+        Err(format!("{s} is neither a snowflake nor a date"))
+    }
+
+    /// `from_id_or_date`, for where a range ends. A date on its own takes in all of that day
+    pub fn until_id_or_date(s: &str) -> Result<Self, String> {
+        if let Ok(date) = chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") && let Some(next) = date.succ_opt() {
+            return Ok(Snowflake(Snowflake::from_datetime(next.and_time(chrono::NaiveTime::MIN).and_utc()).0.saturating_sub(1)));
+        }
+
+        Snowflake::from_id_or_date(s)
+    }
+}
+
+impl std::ops::Deref for Snowflake {
     type Target = u64;
 
     fn deref(&self) -> &Self::Target {
@@ -12,10 +76,29 @@ impl std::ops::Deref for U64ReprStr {
     }
 }
 
-struct U64ReprStrVisitor;
+impl std::str::FromStr for Snowflake {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Snowflake)
+    }
+}
+
+/// Prints the id. The alternate form, `{:#}`, also prints when it was created
+impl std::fmt::Display for Snowflake {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            write!(f, "{} ({})", self.0, self.timestamp().format("%Y-%m-%d %H:%M:%S%.3f UTC"))
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+struct SnowflakeVisitor;
 
-impl<'de> Visitor<'de> for U64ReprStrVisitor {
-    type Value = U64ReprStr;
+impl<'de> Visitor<'de> for SnowflakeVisitor {
+    type Value = Snowflake;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
         formatter.write_str("A string that encoded a 64-bit unsigned integer")
@@ -28,13 +111,20 @@ impl<'de> Visitor<'de> for U64ReprStrVisitor {
         let parsed = v.parse::<u64>();
 
         match parsed {
-            Ok(u) => Ok(U64ReprStr(u)),
+            Ok(u) => Ok(Snowflake(u)),
             Err(_) => Err(E::custom(format!("{} is not a valid u64", v)))
         }
     }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Snowflake(v))
+    }
 }
 
-impl Serialize for U64ReprStr {
+impl Serialize for Snowflake {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer
@@ -43,24 +133,24 @@ impl Serialize for U64ReprStr {
     }
 }
 
-impl<'de> Deserialize<'de> for U64ReprStr {
+impl<'de> Deserialize<'de> for Snowflake {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de>
     {
-        deserializer.deserialize_str(U64ReprStrVisitor)
+        deserializer.deserialize_any(SnowflakeVisitor)
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Settings {
     pub guild_folders: Vec<GuildFolder>,
-    // pub guild_positions: Vec<U64ReprStr>
+    // pub guild_positions: Vec<Snowflake>
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GuildFolder {
-    pub guild_ids: Vec<U64ReprStr>
+    pub guild_ids: Vec<Snowflake>
 }
 
 impl Settings {
@@ -71,40 +161,246 @@ impl Settings {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Guild {
-    pub id: U64ReprStr,
+    pub id: Snowflake,
     pub name: String,
     pub icon: Option<String>,
     pub owner: bool
 }
 
+/// Who the archive in a db directory belongs to, written on every update
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub user: User,
+    pub updated_at: DateTime<Utc>
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
-    pub id: U64ReprStr,
+    pub id: Snowflake,
     pub username: String,
     pub discriminator: String,
     pub avatar: Option<String>
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Stored as Discord's integer, so headers written before this existed still parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "i32", into = "i32")]
+pub enum ChannelType {
+    GuildText,
+    DM,
+    GuildVoice,
+    GroupDM,
+    GuildCategory,
+    GuildAnnouncement,
+    AnnouncementThread,
+    PublicThread,
+    PrivateThread,
+    GuildStageVoice,
+    GuildDirectory,
+    GuildForum,
+    GuildMedia,
+    Unknown(i32)
+}
+
+impl From<i32> for ChannelType {
+    fn from(value: i32) -> Self {
+        match value {
+            0 => ChannelType::GuildText,
+            1 => ChannelType::DM,
+            2 => ChannelType::GuildVoice,
+            3 => ChannelType::GroupDM,
+            4 => ChannelType::GuildCategory,
+            5 => ChannelType::GuildAnnouncement,
+            10 => ChannelType::AnnouncementThread,
+            11 => ChannelType::PublicThread,
+            12 => ChannelType::PrivateThread,
+            13 => ChannelType::GuildStageVoice,
+            14 => ChannelType::GuildDirectory,
+            15 => ChannelType::GuildForum,
+            16 => ChannelType::GuildMedia,
+            other => ChannelType::Unknown(other)
+        }
+    }
+}
+
+impl From<ChannelType> for i32 {
+    fn from(value: ChannelType) -> Self {
+        match value {
+            ChannelType::GuildText => 0,
+            ChannelType::DM => 1,
+            ChannelType::GuildVoice => 2,
+            ChannelType::GroupDM => 3,
+            ChannelType::GuildCategory => 4,
+            ChannelType::GuildAnnouncement => 5,
+            ChannelType::AnnouncementThread => 10,
+            ChannelType::PublicThread => 11,
+            ChannelType::PrivateThread => 12,
+            ChannelType::GuildStageVoice => 13,
+            ChannelType::GuildDirectory => 14,
+            ChannelType::GuildForum => 15,
+            ChannelType::GuildMedia => 16,
+            ChannelType::Unknown(other) => other
+        }
+    }
+}
+
+impl ChannelType {
+    /// Whether the channel itself has a message history we can page through.
+    /// Voice and stage channels count, for their text chat
+    pub fn has_messages(&self) -> bool {
+        matches!(self,
+            ChannelType::GuildText
+            | ChannelType::DM
+            | ChannelType::GuildVoice
+            | ChannelType::GroupDM
+            | ChannelType::GuildAnnouncement
+            | ChannelType::AnnouncementThread
+            | ChannelType::PublicThread
+            | ChannelType::PrivateThread
+            | ChannelType::GuildStageVoice
+        )
+    }
+
+    pub fn is_thread(&self) -> bool {
+        matches!(self, ChannelType::AnnouncementThread | ChannelType::PublicThread | ChannelType::PrivateThread)
+    }
+
+    /// Whether threads can be started in the channel. Forum and media channels only have
+    /// messages through their posts, which are threads
+    pub fn has_threads(&self) -> bool {
+        matches!(self,
+            ChannelType::GuildText
+            | ChannelType::GuildAnnouncement
+            | ChannelType::GuildForum
+            | ChannelType::GuildMedia
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct DMChannel {
-    pub r#type: i32,
-    pub id: U64ReprStr,
-    pub last_message_id: Option<U64ReprStr>,
-    pub recipients: Vec<User>
+    pub r#type: ChannelType,
+    pub id: Snowflake,
+    pub last_message_id: Option<Snowflake>,
+    pub recipients: Vec<User>,
+    // The rest are only set on group DMs
+    pub name: Option<String>,
+    pub icon: Option<String>,
+    pub owner_id: Option<Snowflake>,
+    /// Everyone who was added or left, as far back as the archive goes. Built from the
+    /// recipient add/remove system messages, since `recipients` only says who's in it now
+    #[serde(default)]
+    pub membership: Vec<MembershipChange>
+}
+
+impl DMChannel {
+    pub fn is_group(&self) -> bool {
+        self.r#type == ChannelType::GroupDM
+    }
+
+    /// The group's name if it was given one, otherwise everyone in it
+    pub fn display_name(&self) -> String {
+        self.display_name_for(None)
+    }
+
+    /// Like `display_name`, but leaves out the archiving account so a DM is named after the other person
+    pub fn display_name_for(&self, me: Option<Snowflake>) -> String {
+        match &self.name {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => self.recipients.iter()
+                .filter(|u| Some(u.id) != me)
+                .map(|u| u.username.clone())
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    }
+
+    /// Records a change, unless it's already been seen. Kept in message order. Returns whether it was new
+    pub fn record_membership(&mut self, change: MembershipChange) -> bool {
+        match self.membership.binary_search_by_key(&change.message_id, |c| c.message_id) {
+            Ok(_) => false,
+            Err(index) => {
+                self.membership.insert(index, change);
+
+                true
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MembershipChange {
+    pub message_id: Snowflake,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub user_id: Snowflake,
+    pub username: String,
+    /// Whoever did the adding or removing. Same as `user_id` when someone leaves on their own
+    pub by: Snowflake,
+    pub joined: bool
+}
+
+/// Something about a channel that was different from one update to the next
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelChange {
+    /// When the update that noticed it ran. The change itself happened at some point after the one before
+    pub seen_at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub field: ChangedField
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "field", rename_all = "snake_case")]
+pub enum ChangedField {
+    Name { from: Option<String>, to: Option<String> },
+    Topic { from: Option<String>, to: Option<String> },
+    /// Moved to another category
+    Parent { from: Option<Snowflake>, to: Option<Snowflake> }
+}
+
+impl ChangedField {
+    pub fn describe(&self) -> String {
+        let name = |name: &Option<String>| name.as_ref().map_or_else(|| String::from("(no name)"), |n| format!("#{n}"));
+        let category = |id: &Option<Snowflake>| id.map_or_else(|| String::from("no category"), |id| format!("category {id}"));
+
+        match self {
+            ChangedField::Name { from, to } => format!("channel was renamed from {} to {}", name(from), name(to)),
+            ChangedField::Topic { to: Some(to), .. } => format!("topic was changed to: {to}"),
+            ChangedField::Topic { to: None, .. } => String::from("topic was cleared"),
+            ChangedField::Parent { from, to } => format!("channel was moved from {} to {}", category(from), category(to))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TextChannel {
-    pub r#type: i32,
-    pub id: U64ReprStr,
-    pub guild_id: U64ReprStr,
+    pub r#type: ChannelType,
+    pub id: Snowflake,
+    pub guild_id: Snowflake,
     pub name: String,
-    pub parent_id: Option<U64ReprStr>,
-    pub last_message_id: Option<U64ReprStr>,
-    pub topic: Option<String>
+    pub parent_id: Option<Snowflake>,
+    pub last_message_id: Option<Snowflake>,
+    pub topic: Option<String>,
+    pub thread_metadata: Option<ThreadMetadata>
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThreadMetadata {
+    pub archived: bool,
+    // Kept as it came, since it's what archived thread listings page by
+    pub archive_timestamp: Option<String>,
+    #[serde(default)]
+    pub locked: bool
+}
+
+/// A page of threads, as returned by the active and archived thread listings
+#[derive(Debug, Deserialize)]
+pub struct ThreadList {
+    pub threads: Vec<TextChannel>,
+    #[serde(default)]
+    pub has_more: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Channel {
     DMChannel(DMChannel),
@@ -119,13 +415,26 @@ impl Channel {
         }
     }
 
-    pub fn is_text(&self) -> bool {
+    pub fn kind(&self) -> ChannelType {
         match self {
-            Channel::DMChannel(_) => true,
-            Channel::TextChannel(c) => c.r#type == 0
+            Channel::DMChannel(dmchannel) => dmchannel.r#type,
+            Channel::TextChannel(text_channel) => text_channel.r#type,
         }
     }
 
+    pub fn has_messages(&self) -> bool {
+        self.kind().has_messages()
+    }
+
+    /// Announcement, public and private threads, and forum posts. Their `parent_id` is the channel they were started in
+    pub fn is_thread(&self) -> bool {
+        self.kind().is_thread()
+    }
+
+    pub fn has_threads(&self) -> bool {
+        self.kind().has_threads()
+    }
+
     pub fn last_message_id(&self) -> Option<u64> {
         match self {
             Channel::DMChannel(dmchannel) => dmchannel.last_message_id.map(|id| *id),
@@ -133,21 +442,75 @@ impl Channel {
         }
     }
 
-    pub fn display(&self) -> String {
+    pub fn name(&self) -> Option<&str> {
         match self {
-            Channel::DMChannel(dmchannel) => {
-                let names: Vec<_> = dmchannel.recipients.iter().map(|u| u.username.clone()).collect();
+            Channel::DMChannel(dmchannel) => dmchannel.name.as_deref(),
+            Channel::TextChannel(text_channel) => Some(&text_channel.name),
+        }
+    }
+
+    pub fn topic(&self) -> Option<&str> {
+        match self {
+            Channel::DMChannel(_) => None,
+            Channel::TextChannel(text_channel) => text_channel.topic.as_deref(),
+        }
+    }
+
+    /// The category for channels, or the channel a thread is in. Threads can't move, so for them this never changes
+    pub fn parent_id(&self) -> Option<Snowflake> {
+        match self {
+            Channel::DMChannel(_) => None,
+            Channel::TextChannel(text_channel) => text_channel.parent_id,
+        }
+    }
+
+    /// Whether the two only differ in their last message, which changes with every message and isn't worth keeping track of
+    pub fn same_as(&self, other: &Channel) -> bool {
+        let mut this = self.clone();
 
-                format!("#DM({})", names.join(", "))
-            },
+        match &mut this {
+            Channel::DMChannel(dmchannel) => dmchannel.last_message_id = other.last_message_id().map(Snowflake),
+            Channel::TextChannel(text_channel) => text_channel.last_message_id = other.last_message_id().map(Snowflake),
+        }
+
+        this == *other
+    }
+
+    /// What's different about this channel since it was `old`, out of what's kept in the history
+    pub fn changes_from(&self, old: &Channel) -> Vec<ChangedField> {
+        let mut changes = vec![];
+
+        if self.name() != old.name() {
+            changes.push(ChangedField::Name { from: old.name().map(String::from), to: self.name().map(String::from) });
+        }
+
+        if self.topic() != old.topic() {
+            changes.push(ChangedField::Topic { from: old.topic().map(String::from), to: self.topic().map(String::from) });
+        }
+
+        if self.parent_id() != old.parent_id() {
+            changes.push(ChangedField::Parent { from: old.parent_id(), to: self.parent_id() });
+        }
+
+        changes
+    }
+
+    pub fn display(&self) -> String {
+        match self {
+            Channel::DMChannel(dmchannel) if dmchannel.is_group() => format!("#Group({})", dmchannel.display_name()),
+            Channel::DMChannel(dmchannel) => format!("#DM({})", dmchannel.display_name()),
             Channel::TextChannel(text_channel) => format!("#{}", text_channel.name),
         }
     }
 }
 
+/// Whatever else Discord sent on a record that we don't model (yet). It's flattened back in on save,
+/// so nothing is lost from the archive, and later versions can pick fields out of it
+pub type Extra = serde_json::Map<String, serde_json::Value>;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
-    pub id: U64ReprStr,
+    pub id: Snowflake,
     pub r#type: u32,
     pub timestamp: Option<DateTime<Utc>>,
     pub content: Option<String>,
@@ -156,20 +519,115 @@ pub struct Message {
     pub edited_timestamp: Option<String>,
     pub embeds: Vec<Embed>,
     pub pinned: Option<bool>,
+    /// Earlier versions of the message, oldest first, for every time a re-fetch found it changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub revisions: Vec<Revision>,
+    /// When a rescan first noticed the message was gone. Deleted messages are kept, not removed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Set on messages that came from an import, whose `id` is made up with `Snowflake::synthetic`.
+    /// They're swapped out for the real message if an update ever fetches it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub synthetic: bool,
+    #[serde(flatten)]
+    pub extra: Extra
+}
+
+/// What a message looked like before an edit
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Revision {
+    /// When this version was written. `None` for the original
+    pub edited_timestamp: Option<String>,
+    pub content: Option<String>,
+    pub attachments: Vec<Attachment>,
+    pub embeds: Vec<Embed>
+}
+
+// System message types for group DM membership
+pub const RECIPIENT_ADD: u32 = 1;
+pub const RECIPIENT_REMOVE: u32 = 2;
+
+impl Message {
+    pub fn revision(&self) -> Revision {
+        Revision {
+            edited_timestamp: self.edited_timestamp.clone(),
+            content: self.content.clone(),
+            attachments: self.attachments.clone(),
+            embeds: self.embeds.clone()
+        }
+    }
+
+    /// Whether the visible parts of the message changed. Attachment and embed media urls are signed
+    /// and expire, so they're different on every fetch and don't count
+    pub fn differs_from(&self, other: &Message) -> bool {
+        self.content != other.content
+            || !self.attachments.iter().map(|a| a.id).eq(other.attachments.iter().map(|a| a.id))
+            || self.embeds.len() != other.embeds.len()
+            || self.embeds.iter().zip(&other.embeds).any(|(a, b)| a.differs_from(b))
+    }
+
+    /// Gives messages imported before synthetic ids existed, which were stored with an id of 0, their synthetic id
+    pub fn assign_synthetic_id(&mut self) {
+        if let (0, Some(timestamp)) = (self.id.0, self.timestamp) {
+            self.id = Snowflake::synthetic(timestamp, self.author.id, self.content.as_deref().unwrap_or(""));
+            self.synthetic = true;
+        }
+    }
+
+    /// Whether this is an imported copy of `real`, a message fetched from the API. Imports only
+    /// have their timestamps down to the second, and their text trimmed
+    pub fn is_placeholder_for(&self, real: &Message) -> bool {
+        self.synthetic
+            && self.author.id == real.author.id
+            && self.timestamp.map(|t| t.trunc_subsecs(0)) == real.timestamp.map(|t| t.trunc_subsecs(0))
+            && self.content.as_deref().map(str::trim) == real.content.as_deref().map(str::trim)
+    }
+
+    /// Takes the place of `old`, a previously stored copy of this message, keeping its history
+    pub fn supersede(&mut self, old: &Message) {
+        self.revisions = old.revisions.clone();
+
+        if old.differs_from(self) {
+            self.revisions.push(old.revision());
+        }
+    }
+
+    /// The membership change a recipient add/remove system message stands for
+    pub fn membership_change(&self) -> Option<MembershipChange> {
+        let joined = match self.r#type {
+            RECIPIENT_ADD => true,
+            RECIPIENT_REMOVE => false,
+            _ => return None
+        };
+
+        // The affected user is the only mention
+        let user = self.extra.get("mentions")?.as_array()?.first()?;
+
+        Some(MembershipChange {
+            message_id: self.id,
+            timestamp: self.timestamp,
+            user_id: user.get("id")?.as_str()?.parse().ok()?,
+            username: user.get("username")?.as_str()?.to_string(),
+            by: self.author.id,
+            joined
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Author {
     pub username: String,
     pub avatar: Option<String>,
-    pub id: String,
-    pub global_name: Option<String>
+    pub id: Snowflake,
+    pub global_name: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra
 }
 
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Attachment {
-    pub id: String,
+    pub id: Snowflake,
     pub filename: String,
     pub url: String,
     pub height: Option<usize>,
@@ -178,23 +636,36 @@ pub struct Attachment {
     pub original_content_type: Option<String>,
     pub size: Option<u64>,
     pub proxy_url: Option<String>,
+    #[serde(flatten)]
+    pub extra: Extra
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Embed {
-    r#type: Option<String>,
+    pub r#type: Option<String>,
     author: Option<EmbedAuthor>,
     thumbnail: Option<EmbedThumbnail>,
-    title: Option<String>,
-    url: Option<String>,
-    description: Option<String>,
+    pub title: Option<String>,
+    pub url: Option<String>,
+    pub description: Option<String>,
     fields: Option<Vec<EmbedField>>,
     footer: Option<EmbedFooter>,
     timestamp: Option<String>,
-    color: Option<u32>
+    color: Option<u32>,
+    #[serde(flatten)]
+    extra: Extra
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Embed {
+    fn differs_from(&self, other: &Embed) -> bool {
+        self.title != other.title
+            || self.description != other.description
+            || self.url != other.url
+            || self.fields != other.fields
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct EmbedField {
     name: String,
     value: String,
@@ -205,7 +676,9 @@ pub struct EmbedField {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbedAuthor {
     name: Option<String>,
-    url: Option<String>
+    url: Option<String>,
+    #[serde(flatten)]
+    extra: Extra
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -215,10 +688,81 @@ pub struct EmbedThumbnail {
     proxy_url: Option<String>,
     width: Option<usize>,
     height: Option<usize>,
+    #[serde(flatten)]
+    extra: Extra
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct EmbedFooter {
     text: Option<String>,
-    icon_url: Option<String>
+    icon_url: Option<String>,
+    #[serde(flatten)]
+    extra: Extra
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::message;
+
+    use super::*;
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn synthetic_ids_are_stable() {
+        let id = Snowflake::synthetic(at("2019-06-07T03:14:05Z"), Snowflake(42), "My God.");
+
+        // Written to disk, so this can't change between releases
+        assert_eq!(id, Snowflake(586392409213352703));
+        assert_eq!(id, Snowflake::synthetic(at("2019-06-07T03:14:05Z"), Snowflake(42), "My God."));
+    }
+
+    #[test]
+    fn synthetic_ids_sort_at_their_timestamp() {
+        let timestamp = at("2019-06-07T03:14:05Z");
+        let id = Snowflake::synthetic(timestamp, Snowflake(42), "My God.");
+
+        assert_eq!(id.timestamp(), timestamp);
+        assert!(id >= Snowflake::from_datetime(timestamp));
+        assert!(id < Snowflake::from_datetime(timestamp + chrono::TimeDelta::milliseconds(1)));
+        assert_ne!(id, Snowflake::synthetic(timestamp, Snowflake(43), "My God."));
+        assert_ne!(id, Snowflake::synthetic(timestamp, Snowflake(42), "My God!"));
+    }
+
+    #[test]
+    fn id_zero_imports_get_their_synthetic_id() {
+        let mut imported = message(0, "2019-06-07T03:14:05Z", 42, "My God.");
+
+        imported.assign_synthetic_id();
+
+        assert!(imported.synthetic);
+        assert_eq!(imported.id, Snowflake::synthetic(at("2019-06-07T03:14:05Z"), Snowflake(42), "My God."));
+    }
+
+    #[test]
+    fn placeholders_match_to_the_second() {
+        let mut placeholder = message(0, "2019-06-07T03:14:05Z", 42, "My God. ");
+
+        placeholder.assign_synthetic_id();
+
+        let real = message(*Snowflake::from_datetime(at("2019-06-07T03:14:05.678Z")), "2019-06-07T03:14:05.678Z", 42, "My God.");
+
+        assert!(placeholder.is_placeholder_for(&real));
+        assert!(!real.is_placeholder_for(&placeholder));
+        assert!(!placeholder.is_placeholder_for(&message(1, "2019-06-07T03:14:06Z", 42, "My God.")));
+        assert!(!placeholder.is_placeholder_for(&message(1, "2019-06-07T03:14:05Z", 43, "My God.")));
+        assert!(!placeholder.is_placeholder_for(&message(1, "2019-06-07T03:14:05Z", 42, "My God!")));
+    }
+
+    #[test]
+    fn a_date_until_takes_in_the_whole_day() {
+        let until = Snowflake::until_id_or_date("2024-01-31").unwrap();
+
+        assert!(until > Snowflake::from_datetime(at("2024-01-31T23:59:59.999Z")));
+        assert!(until < Snowflake::from_datetime(at("2024-02-01T00:00:00Z")));
+        assert_eq!(Snowflake::until_id_or_date("2024-01-31T12:00:00").unwrap(), Snowflake::from_datetime(at("2024-01-31T12:00:00Z")));
+        assert_eq!(Snowflake::until_id_or_date("1234").unwrap(), Snowflake(1234));
+    }
 }
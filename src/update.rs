@@ -1,12 +1,23 @@
+use std::collections::HashSet;
+
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use anyhow::Context;
 
-use crate::api::{fetch_channel, fetch_channels, fetch_dms, fetch_guilds};
+use crate::api::{ApiError, ArchivedThreads, DiscordClient};
 use crate::args::Update;
-use crate::fs::update_channels;
-use crate::types::Channel;
+use crate::config::Paths;
+use crate::fs::{DbLock, update_channels};
+use crate::shutdown::Shutdown;
+use crate::store::Archives;
+use crate::types::{Account, Channel};
+
+/// Channels with messages of their own, and forums, which hold on to their posts' threads.
+/// Categories, directories and the like are dropped
+fn is_archivable(channel: &Channel) -> bool {
+    channel.has_messages() || channel.has_threads()
+}
 
-async fn populate_interactive_channels(options: &mut Update) -> Option<()> {
+async fn populate_interactive_channels(options: &mut Update, client: &DiscordClient) -> Option<()> {
     let mut input_lines = BufReader::new(io::stdin()).lines();
 
     loop {
@@ -15,7 +26,14 @@ async fn populate_interactive_channels(options: &mut Update) -> Option<()> {
         let response = input_lines.next_line().await.ok()??;
         match response.trim() {
             "y" | "yes" => {
-                let dms = fetch_dms(options).await;
+                let dms = match client.fetch_dms().await {
+                    Ok(dms) => dms,
+                    Err(e) => {
+                        eprintln!("Couldn't fetch DMs: {e}");
+
+                        return None;
+                    }
+                };
 
                 println!("Added {} DMs", dms.len());
 
@@ -32,7 +50,14 @@ async fn populate_interactive_channels(options: &mut Update) -> Option<()> {
         }
     }
 
-    let guilds = fetch_guilds(options).await;
+    let guilds = match client.fetch_guilds().await {
+        Ok(guilds) => guilds,
+        Err(e) => {
+            eprintln!("Couldn't fetch guilds: {e}");
+
+            return None;
+        }
+    };
 
     for guild in guilds {
         let can_start = !options.state.channels.is_empty();
@@ -62,10 +87,17 @@ async fn populate_interactive_channels(options: &mut Update) -> Option<()> {
 
             match response.trim() {
                 "y" | "yes" => {
-                    let channels = fetch_channels(options, *guild.id).await;
+                    let channels = match client.fetch_channels(*guild.id).await {
+                        Ok(channels) => channels,
+                        Err(e) => {
+                            eprintln!("Couldn't fetch channels in {}: {e}", guild.name);
+
+                            break;
+                        }
+                    };
                     let additions: Vec<_> = channels.into_iter()
                         .map(Channel::TextChannel)
-                        .filter(|c| c.is_text())
+                        .filter(is_archivable)
                         .collect();
 
                     println!("Added {} channels", additions.len());
@@ -87,30 +119,123 @@ async fn populate_interactive_channels(options: &mut Update) -> Option<()> {
     Some(())
 }
 
-pub async fn do_update(mut options: Update) -> Result<(), anyhow::Error> {
+/// Finds the active and archived threads of every selected channel, so they can be archived alongside them
+async fn populate_threads(options: &mut Update, client: &DiscordClient) -> Result<(), ApiError> {
+    let mut known: HashSet<u64> = options.state.channels.iter().map(|c| c.id()).collect();
+    let mut guild_ids = vec![];
+    let mut parents = HashSet::new();
+
+    for channel in options.state.channels.iter() {
+        if let Channel::TextChannel(c) = channel && channel.has_threads() {
+            parents.insert(*c.id);
+
+            if !guild_ids.contains(&*c.guild_id) {
+                guild_ids.push(*c.guild_id);
+            }
+        }
+    }
+
+    let mut threads = vec![];
+
+    for guild_id in guild_ids {
+        match client.fetch_active_threads(guild_id).await {
+            Ok(active) => threads.extend(active),
+            Err(e @ ApiError::Unauthorized(_)) => return Err(e),
+            Err(e) => eprintln!("Couldn't list active threads in guild {guild_id}: {e}")
+        }
+    }
+
+    for &parent in parents.iter() {
+        for kind in [ArchivedThreads::Public, ArchivedThreads::Private, ArchivedThreads::JoinedPrivate] {
+            match client.fetch_archived_threads(parent, kind).await {
+                Ok(archived) => threads.extend(archived),
+                Err(e @ ApiError::Unauthorized(_)) => return Err(e),
+                // Private listings need Manage Threads, it's normal to not have it
+                Err(ApiError::Forbidden(_)) => {},
+                Err(e) => eprintln!("Couldn't list {kind:?} threads in {parent}: {e}")
+            }
+        }
+    }
+
+    let mut added = 0;
+
+    for thread in threads {
+        let in_selection = thread.parent_id.is_some_and(|p| parents.contains(&*p));
+
+        if in_selection && known.insert(*thread.id) {
+            options.state.channels.push(Channel::TextChannel(thread));
+            added += 1;
+        }
+    }
+
+    println!("Added {added} threads");
+
+    Ok(())
+}
+
+pub async fn do_update(mut options: Update, paths: &Paths) -> Result<(), anyhow::Error> {
     // dbg!(&options.channels);
 
+    let client = DiscordClient::new(&options.client)?;
+
+    // Also doubles as a check that the token works before we go asking about anything else
+    let me = client.fetch_me().await?;
+    let db = &paths.db;
+    let _lock = DbLock::acquire(db, options.wait).await?;
+
+    if let Some(previous) = Account::load(db).await && previous.user.id != me.id {
+        eprintln!("warning: this archive was last updated by {} ({}), not {} ({})", previous.user.username, previous.user.id, me.username, me.id);
+    }
+
+    eprintln!("updating as: {} ({})", me.username, me.id);
+
+    Account { user: me, updated_at: chrono::Utc::now() }.save(db).await?;
+
     if options.channels.is_empty() && options.guilds.is_empty() {
-        populate_interactive_channels(&mut options).await.context("what")?;
+        populate_interactive_channels(&mut options, &client).await.context("what")?;
     } else {
         for channel in options.channels.iter() {
-            let channel = fetch_channel(&options, channel.parse().unwrap()).await;
+            let channel = match client.fetch_channel(channel.parse().unwrap()).await {
+                Ok(channel) => channel,
+                Err(e @ ApiError::Unauthorized(_)) => return Err(e.into()),
+                Err(e) => {
+                    eprintln!("skipping channel {channel}: {e}");
+
+                    continue;
+                }
+            };
 
             options.state.channels.push(channel);
         }
 
         for guild in options.guilds.iter() {
-            let channels = fetch_channels(&options, guild.parse().unwrap()).await;
+            let channels = match client.fetch_channels(guild.parse().unwrap()).await {
+                Ok(channels) => channels,
+                Err(e @ ApiError::Unauthorized(_)) => return Err(e.into()),
+                Err(e) => {
+                    eprintln!("skipping guild {guild}: {e}");
+
+                    continue;
+                }
+            };
 
             options.state.channels.reserve(channels.len());
             options.state.channels.extend(
                 channels.into_iter()
                     .map(Channel::TextChannel)
+                    .filter(is_archivable)
             );
         }
     }
 
-    update_channels(&options).await;
+    if !options.skip_threads {
+        populate_threads(&mut options, &client).await?;
+    }
+
+    let archives = Archives::open(paths, options.compress)?;
+    let shutdown = Shutdown::install();
+
+    update_channels(&options, &client, &shutdown, &archives).await;
 
     Ok(())
 }
@@ -0,0 +1,205 @@
+use std::{collections::HashSet, path::Path};
+
+use chrono::TimeDelta;
+use walkdir::WalkDir;
+
+use crate::{args::Verify, config::Paths, fs::{CorruptFrame, DexLines, Header, JOURNAL_PREFIX, Journal, ParsedDex, dex_path, is_compressed, is_dex, toggle_compressed}, types::Message};
+
+// How far a message's timestamp can be from the one in its id before it's reported
+const TIMESTAMP_TOLERANCE: TimeDelta = TimeDelta::seconds(1);
+
+/// What a single archive came out with
+#[derive(Debug, Default)]
+struct Report {
+    problems: Vec<String>,
+    warnings: Vec<String>
+}
+
+pub async fn do_verify(verify: Verify, paths: &Paths) -> anyhow::Result<()> {
+    paths.require_dex("verify")?;
+
+    let mut files = 0;
+    let mut problems = 0;
+    let mut warnings = 0;
+
+    for entry in WalkDir::new(&paths.db) {
+        let entry = entry?;
+
+        if !is_dex(entry.path()) {
+            continue;
+        }
+
+        // One archive that can't be read shouldn't keep the rest from being checked
+        let report = match verify_file(&verify, paths, entry.path()).await {
+            Ok(report) => report,
+            Err(e) => {
+                // Archive errors start with the path, which is printed anyway
+                let error = e.to_string();
+                let error = error.strip_prefix(&format!("{}: ", entry.path().display())).unwrap_or(&error);
+
+                Report { problems: vec![format!("couldn't be checked: {error}")], ..Default::default() }
+            }
+        };
+
+        for problem in &report.problems {
+            println!("{}: {problem}", entry.path().display());
+        }
+
+        for warning in &report.warnings {
+            println!("{}: warning: {warning}", entry.path().display());
+        }
+
+        files += 1;
+        problems += report.problems.len();
+        warnings += report.warnings.len();
+    }
+
+    eprintln!("verified {files} archives: {problems} problems, {warnings} warnings");
+
+    if problems > 0 || (verify.strict && warnings > 0) {
+        return Err(anyhow::anyhow!("{} has problems", paths.db.display()));
+    }
+
+    Ok(())
+}
+
+async fn verify_file(verify: &Verify, paths: &Paths, path: &Path) -> anyhow::Result<Report> {
+    let mut report = Report::default();
+
+    // The lines as they are on disk first, since parsing sorts and dedupes them
+    let mut ids = HashSet::new();
+    let mut synthetic = 0;
+    let mut out_of_order = 0;
+    let mut newest = None;
+    let mut unreadable = false;
+
+    let mut lines = DexLines::open(path)?;
+    let mut line = vec![];
+    let mut line_number = 0;
+
+    loop {
+        match lines.next_line(&mut line) {
+            Ok(Some(_)) => {},
+            Ok(None) => break,
+            Err(ref e) if let Some(corrupt) = CorruptFrame::of(e) => {
+                line_number += 1;
+
+                report.problems.push(format!("{corrupt}, compact --salvage drops what's left of it and keeps a copy of the file"));
+                unreadable = true;
+
+                lines.skip_frame()?;
+
+                continue;
+            },
+            Err(e) => return Err(e.into())
+        }
+
+        line_number += 1;
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if line_number == 1 {
+            if let Err(e) = Header::from_line(&line) {
+                report.problems.push(format!("line 1: bad header, nothing else can be checked: {e}"));
+
+                return Ok(report);
+            }
+
+            continue;
+        }
+
+        if line.starts_with(JOURNAL_PREFIX.as_bytes()) {
+            if let Err(e) = serde_json::from_slice::<Journal>(&line) {
+                report.problems.push(format!("line {line_number}: unreadable journal entry: {e}"));
+                unreadable = true;
+            }
+
+            continue;
+        }
+
+        let message = match serde_json::from_slice::<Message>(&line) {
+            Ok(message) => message,
+            Err(e) => {
+                report.problems.push(format!("line {line_number}: unreadable message: {e}"));
+                unreadable = true;
+
+                continue;
+            }
+        };
+
+        // Backfills append older messages after newer ones, so it's not damage, but parsing has to sort them
+        if newest.is_some_and(|newest| message.id < newest) {
+            out_of_order += 1;
+        }
+
+        newest = newest.max(Some(message.id));
+
+        if message.id.0 == 0 {
+            report.problems.push(format!("line {line_number}: message with id 0, compact gives it a made up id"));
+        } else if !ids.insert(message.id) {
+            report.problems.push(format!("line {line_number}: {} is stored more than once", message.id));
+        }
+
+        if message.synthetic {
+            synthetic += 1;
+        }
+    }
+
+    if lines.torn_frame().is_some() {
+        report.problems.push(String::from("the last save was cut off, the next update or compact drops it"));
+    }
+
+    if line_number == 0 {
+        report.problems.push(String::from("empty file, not even a header"));
+
+        return Ok(report);
+    }
+
+    if out_of_order > 0 {
+        report.warnings.push(format!("{out_of_order} messages are stored out of order, compact sorts them"));
+    }
+
+    if synthetic > 0 {
+        report.warnings.push(format!("{synthetic} imported messages with made up ids"));
+    }
+
+    if unreadable {
+        report.problems.push(String::from("skipped the remaining checks, compact --salvage can set the bad lines aside"));
+
+        return Ok(report);
+    }
+
+    let parsed = ParsedDex::parse(path, false).await?;
+    let expected = dex_path(&paths.db, &parsed.header.channel);
+
+    // Compressed or not, it's the same archive
+    let uncompressed = if is_compressed(path) { toggle_compressed(path) } else { path.to_path_buf() };
+
+    if expected != uncompressed {
+        report.problems.push(format!("header says {} belongs at {}", parsed.header.channel.display(), expected.display()));
+    }
+
+    // Order was checked on disk above. Parsing sorts by id, so here it'd only repeat the timestamp check
+    for message in parsed.messages.iter() {
+        let Some(timestamp) = message.timestamp else {
+            continue;
+        };
+
+        if !message.synthetic && (message.id.timestamp() - timestamp).abs() > TIMESTAMP_TOLERANCE {
+            report.problems.push(format!("{} has a timestamp of {timestamp}, which doesn't match its id", message.id));
+        }
+    }
+
+    let gaps = parsed.suspicious_gaps(verify.gaps.gap_factor, TimeDelta::hours(verify.gaps.min_gap_hours));
+
+    for gap in gaps {
+        report.warnings.push(format!(
+            "nothing between {:#} and {:#} ({} days), messages may be missing",
+            gap.after, gap.before, gap.duration().num_days()
+        ));
+    }
+
+    Ok(report)
+}